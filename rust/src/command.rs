@@ -1,7 +1,9 @@
-use crate::select::{self, Entries, Entry, Select};
+use crate::select;
 use crate::config::Config;
-use std::collections::BTreeSet;
+use crate::report::Format;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::PermissionsExt;
 
 macro_rules! esc {
@@ -27,7 +29,18 @@ pub struct Command {
     pub action: Action,
     pub sandbox: bool,
     pub overwrite: bool,
-    pub critical: bool,
+    /// Where to restore instead of the original location
+    pub to: Option<File>,
+    /// Keep the original path of restored files inside `to`, or of
+    /// extracted ones inside `extract`
+    pub parents: bool,
+    /// Where `--extract` copies the selected entries
+    pub extract: Option<File>,
+    /// Path inside the selected directories for `--cat` and `--extract`
+    pub inside: Option<File>,
+    /// Fix the problems found by `--fsck`
+    pub repair: bool,
+    pub format: Format,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -35,10 +48,14 @@ pub enum Action {
     Remove(Vec<File>),
     Edit(Editor, Selector),
     Help(Vec<Help>),
+    Gc,
+    Fsck,
+    ImportXdg,
+    ImportLegacy,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct File(String);
+pub struct File(OsString);
 
 impl File {
     pub fn make(&self) -> std::path::PathBuf {
@@ -46,7 +63,7 @@ impl File {
     }
 
     pub fn contents(self) -> String {
-        self.0
+        self.0.to_string_lossy().to_string()
     }
 }
 
@@ -117,34 +134,47 @@ impl Block {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Time(String);
 impl Time {
-    fn delta_time(s: &str) -> Result<u64, Error> {
-        let mut acc = 0;
-        let mut curr = None;
+    /// Parse a duration such as `3D4h` into seconds.
+    pub fn delta_time(s: &str) -> Result<u64, Error> {
+        let too_long = || Error::WrongDuration(s.to_string(), None);
+        let mut acc: u64 = 0;
+        let mut curr: Option<u64> = None;
         for c in s.chars() {
             match c {
                 '0'..='9' => {
-                    let base = curr.unwrap_or(0) * 10;
                     let add = c.to_digit(10).unwrap() as u64;
-                    curr = Some(base + add);
+                    let value = curr.unwrap_or(0).checked_mul(10);
+                    curr = Some(value.and_then(|v| v.checked_add(add)).ok_or_else(too_long)?);
                 }
-                's' | 'm' | 'h' | 'D' | 'W' | 'M' | 'Y' => {
+                's' | 'm' | 'h' | 'd' | 'D' | 'W' | 'M' | 'Y' => {
                     let multiplier = match c {
                         's' => 1,
                         'm' => 60,
                         'h' => 60 * 60,
-                        'D' => 60 * 60 * 24,
+                        'd' | 'D' => 60 * 60 * 24,
                         'W' => 60 * 60 * 24 * 7,
                         'M' => 60 * 60 * 24 * 30,
                         'Y' => 60 * 60 * 24 * 365,
                         _ => unreachable!(),
                     };
-                    acc += curr.unwrap_or(1) * multiplier;
+                    acc = curr
+                        .unwrap_or(1)
+                        .checked_mul(multiplier)
+                        .and_then(|secs| acc.checked_add(secs))
+                        .ok_or_else(too_long)?;
                     curr = None;
                 }
                 c if c.is_whitespace() => {}
-                _ => return Err(Error::WrongDuration(s.to_string(), c)),
+                _ => return Err(Error::WrongDuration(s.to_string(), Some(c))),
             }
         }
+        // Trailing digits without a unit are interpreted as days
+        if let Some(days) = curr {
+            acc = days
+                .checked_mul(60 * 60 * 24)
+                .and_then(|secs| acc.checked_add(secs))
+                .ok_or_else(too_long)?;
+        }
         Ok(acc)
     }
 
@@ -171,11 +201,37 @@ impl Time {
     }
 }
 
+/// Parse a size such as `500M` or `2G` into bytes.
+/// Units are powers of 1024, and a bare number is in bytes.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, ""),
+    };
+    let shift = match unit.strip_suffix('B').unwrap_or(unit) {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(Error::InvalidSize(s.to_string())),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| Error::InvalidSize(s.to_string()))
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Editor {
     Delete,
     Restore,
     Info,
+    Stats,
+    Cat,
+    Extract,
     Null,
 }
 
@@ -185,22 +241,12 @@ impl Editor {
             Editor::Delete => "del",
             Editor::Restore => "rest",
             Editor::Info => "info",
+            Editor::Stats => "stats",
+            Editor::Cat => "cat",
+            Editor::Extract => "extract",
             Editor::Null => "null",
         }
     }
-
-    pub fn run<'i>(self, cfg: &Config, entries: &'i Entries, selection: &BTreeSet<(usize, &'i Entry)>) {
-        match self {
-            Editor::Delete => entries.delete(cfg, selection),
-            Editor::Restore => entries.restore(cfg, selection),
-            Editor::Info => entries.info(cfg, selection),
-            Editor::Null => {
-                for (num, entry) in selection {
-                    println!("{} {}", num, entry.true_name());
-                }
-            }
-        }
-    }
 }
 
 #[derive(Debug, Default, PartialEq, Eq)]
@@ -248,7 +294,8 @@ pub enum Error {
     InvalidIndex(String),
     UnknownArg(String),
     UselessSelector(&'static str, Selector),
-    WrongDuration(String, char),
+    /// With the invalid character, if any, or else too long
+    WrongDuration(String, Option<char>),
     InvalidRegexSyntax(String, String),
     RegexFailure(String),
     FileDoesNotExist(String),
@@ -257,19 +304,39 @@ pub enum Error {
     ExecError(&'static str),
     CouldNotCreateDir(String),
     CouldNotMove(String, String),
-    InvalidVarLs(String),
+    CouldNotRemove(String),
     InvalidVarFzf(String),
     NoInstalledFzf,
     HistoryNotReadable(String),
     MissingData(String, usize, &'static str),
     HelpNotFound(String),
     CorruptedTimestamp(String),
+    CorruptedName(String),
+    UnsupportedHistory(String, u32),
+    Locked(String, Option<i32>),
+    InvalidFormat(String),
+    NoExpiry,
+    InvalidSize(String),
+    QuotaExceeded(u64, u64),
+    LargerThanQuota(u64, u64),
+    InvalidTrashInfo(String),
+    MissingValue(&'static str),
+    /// With the options that would give it a use
+    UselessOption(&'static str, &'static [&'static str]),
+    ImportIntoItself(String),
+    NoLegacyTrash(String),
+    CouldNotCopy(String, String),
+    NotRegularFile(String),
+    OutsideEntry(String),
+    NoCurrentDir,
     SandBoxed,
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let (title, message, help) = match self {
+impl Error {
+    /// Title, explanation and hint, uncolored.
+    /// Errors that are not meant to be reported have none.
+    pub fn parts(&self) -> Option<(String, String, String)> {
+        Some(match self {
             Error::NonExclusiveCmd(prev, new) => (
                 format!("Non-exclusive command"),
                 format!("'{}' is given, but '{}' was already registered", new, prev),
@@ -312,11 +379,16 @@ impl fmt::Display for Error {
                 ),
                 format!("remove all selection arguments"),
             ),
-            Error::WrongDuration(dur, c) => (
+            Error::WrongDuration(dur, Some(c)) => (
                 format!("Wrong duration"),
                 format!("'{}' cannot be parsed as a time delta", dur),
                 format!("remove invalid character '{}'", c),
             ),
+            Error::WrongDuration(dur, None) => (
+                format!("Wrong duration"),
+                format!("'{}' is too long to be counted in seconds", dur),
+                format!("use a shorter duration"),
+            ),
             Error::InvalidRegexSyntax(re, err) => (
                 format!("Invalid regex syntax"),
                 format!("'{}' is not a regex", re),
@@ -362,10 +434,10 @@ impl fmt::Display for Error {
                 format!("unable to move '{}' to '{}'", src, dest),
                 format!("check write permissions"),
             ),
-            Error::InvalidVarLs(cmd) => (
-                format!("Invalid $REM_LS contents"),
-                format!("'{}' is invalid", cmd),
-                format!("it must be one of 'exa' or 'ls' _and_ be in your $PATH"),
+            Error::CouldNotRemove(dir) => (
+                format!("Failed to delete"),
+                format!("unable to delete '{}'", dir),
+                format!("check write permissions"),
             ),
             Error::InvalidVarFzf(cmd) => (
                 format!("Invalid $REM_FZF contents"),
@@ -390,14 +462,128 @@ impl fmt::Display for Error {
             Error::HelpNotFound(menu) => (
                 format!("Help menu not found"),
                 format!("'{}' does not exist", menu),
-                format!("use one of examples/cmd/select/info/rest/undo/del/pat/fzf/idx/main"),
+                format!("use one of examples/cmd/select/info/rest/undo/del/pat/fzf/idx/format/gc/stats/fsck/import/cat/main"),
             ),
             Error::CorruptedTimestamp(ts) => (
                 format!("Unreadable timestamp"),
                 format!("'{}' cannot be parsed as a u64", ts),
                 format!("change to valid 64-bit unsigned integer: {} to {}", 0, u64::MAX),
             ),
-            Error::SandBoxed => return Ok(()),
+            Error::CorruptedName(name) => (
+                format!("Unreadable file name"),
+                format!("'{}' contains an invalid escape sequence", name),
+                format!("only '\\\\' and '\\xHH' are allowed"),
+            ),
+            Error::UnsupportedHistory(header, version) => (
+                format!("Unsupported history format"),
+                format!("history starts with '{}'", header),
+                format!("this version of Rem only reads formats up to {}", version),
+            ),
+            Error::Locked(lock, Some(pid)) => (
+                format!("Another Rem process is running"),
+                format!("'{}' is held by process {}", lock, pid),
+                format!("wait for it to terminate before running Rem again"),
+            ),
+            Error::Locked(lock, None) => (
+                format!("Another Rem process is running"),
+                format!("'{}' exists but does not name its owner", lock),
+                format!("if no other Rem process is running, delete it manually"),
+            ),
+            Error::InvalidFormat(fmt) => (
+                format!("Invalid output format"),
+                format!("'{}' is not a known format", fmt),
+                format!("use one of human/json/tsv"),
+            ),
+            Error::NoExpiry => (
+                format!("No retention policy"),
+                format!("'--gc' purges expired entries, but no maximum age is configured"),
+                format!("set $REM_EXPIRE, e.g. REM_EXPIRE=30D"),
+            ),
+            Error::InvalidSize(size) => (
+                format!("Invalid size"),
+                format!("'{}' cannot be parsed as a size", size),
+                format!("write it as a number of bytes, optionally followed by K, M, G or T"),
+            ),
+            Error::MissingValue(option) => (
+                format!("Missing value"),
+                format!("'--{}' expects an argument", option),
+                format!("provide one right after it"),
+            ),
+            Error::UselessOption(option, needed) => {
+                let needed = needed
+                    .iter()
+                    .map(|opt| format!("'--{}'", opt))
+                    .collect::<Vec<_>>()
+                    .join(" or ");
+                (
+                    format!("Useless option"),
+                    format!("'--{}' has no effect without {}", option, needed),
+                    format!("remove '--{}' or add {}", option, needed),
+                )
+            }
+            Error::InvalidTrashInfo(file) => (
+                format!("Invalid trash info"),
+                format!("'{}' does not say where the file came from", file),
+                format!("restore it with the program that removed it"),
+            ),
+            Error::ImportIntoItself(trash) => (
+                format!("Nothing to import"),
+                format!("'{}' is already the trash in use", trash),
+                format!("its files can be selected as they are"),
+            ),
+            Error::NoLegacyTrash(root) => (
+                format!("No trash to import"),
+                format!("'{}' does not hold a history and a registry", root),
+                format!("set $REM_LEGACY_ROOT to where the shell version kept its files"),
+            ),
+            Error::CouldNotCopy(src, dest) => (
+                format!("Failed to copy"),
+                format!("unable to copy '{}' to '{}'", src, dest),
+                format!("check read and write permissions"),
+            ),
+            Error::NotRegularFile(path) => (
+                format!("Not a regular file"),
+                format!("'{}' cannot be printed", path),
+                format!("copy it out with '--extract DEST' instead"),
+            ),
+            Error::OutsideEntry(path) => (
+                format!("Path outside of the entry"),
+                format!("'{}' does not point inside the removed file", path),
+                format!("give it relative to the removed directory, or as it was before removal"),
+            ),
+            Error::NoCurrentDir => (
+                format!("No current directory"),
+                format!("relative paths cannot be resolved from a directory that is not accessible"),
+                format!("change to an existing directory or give absolute paths"),
+            ),
+            Error::QuotaExceeded(needed, quota) => (
+                format!("Trash quota exceeded"),
+                format!(
+                    "removal would bring the trash to {} out of {}",
+                    crate::meta::human_size(*needed),
+                    crate::meta::human_size(*quota)
+                ),
+                format!("free some space with '--del', or unset $REM_QUOTA_STRICT to evict old entries"),
+            ),
+            Error::LargerThanQuota(size, quota) => (
+                format!("Trash quota exceeded"),
+                format!(
+                    "the files to remove take {} but the quota is {}",
+                    crate::meta::human_size(*size),
+                    crate::meta::human_size(*quota)
+                ),
+                format!("raise $REM_QUOTA or use plain `rm`"),
+            ),
+            Error::SandBoxed => return None,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (title, message, help) = match self.parts() {
+            Some(parts) => parts,
+            None => return Ok(()),
         };
         writeln!(f, "{}{}{}", esc![BOLD;RED], title, esc![])?;
         writeln!(f, "  {}", message)?;
@@ -427,8 +613,8 @@ macro_rules! do_take_while {
     ( $args:expr, $label:expr, $( $insertion:tt )+ ) => {{
         let mut first = true;
         while let Some(s) = $args.peek() {
-            if first || s.as_ref().chars().next() != Some('-') {
-                $( $insertion )+(String::from($args.next().unwrap().as_ref()));
+            if first || !s.as_ref().as_bytes().starts_with(b"-") {
+                $( $insertion )+($args.next().unwrap().as_ref().to_string_lossy().to_string());
             } else {
                 break;
             }
@@ -442,62 +628,128 @@ macro_rules! do_take_while {
 
 impl Command {
     pub fn argparse() -> Result<Self, Error> {
-        Self::parse(std::env::args().skip(1))
+        Self::parse(std::env::args_os().skip(1))
     }
 
     pub fn parse<I, S>(args: I) -> Result<Self, Error>
     where
         I: IntoIterator<Item = S>,
-        S: AsRef<str>,
+        S: AsRef<OsStr>,
     {
         let mut pos_args = Vec::new();
         let mut selector = Selector::default();
         let mut help = false;
         let mut undo = false;
+        // Maintenance commands that act on the trash as a whole
+        let mut task = None;
         let mut editor = OnceEd::new();
         let mut sandbox = false;
         let mut overwrite = false;
+        let mut to = None;
+        let mut parents = false;
+        let mut extract = None;
+        let mut inside = None;
+        let mut repair = false;
+        let mut format = Format::Human;
         let mut args = args.into_iter().peekable();
         loop {
             match args.next() {
                 None => break,
-                Some(arg) => match arg.as_ref() {
-                    "--info" | "-i" => editor.set(Editor::Info)?,
-                    "--help" | "-h" => help = true,
-                    "--undo" | "-u" => undo = true,
-                    "--rest" | "-r" => editor.set(Editor::Restore)?,
-                    "--del" | "-d" => editor.set(Editor::Delete)?,
-                    "--fzf" | "-F" => selector.add_fzf(),
-                    "--pat" | "-P" => do_take_while!(args, "pat", selector.add_pat),
-                    "--idx" | "-I" => do_take_while!(args, "idx", selector.add_idx),
-                    "--blk" | "-B" => do_take_while!(args, "blk", selector.add_blk),
-                    "--time" | "-T" => do_take_while!(args, "time", selector.add_time),
-                    "--sandbox" | "-S" => sandbox = true,
-                    "--overwrite" | "-O" => overwrite = true,
-                    "--" => break,
+                Some(arg) => match arg.as_ref().to_str() {
+                    Some("--info") | Some("-i") => editor.set(Editor::Info)?,
+                    Some("--help") | Some("-h") => help = true,
+                    Some("--undo") | Some("-u") => undo = true,
+                    Some("--gc") => set_task(&mut task, "gc", Action::Gc)?,
+                    Some("--fsck") => set_task(&mut task, "fsck", Action::Fsck)?,
+                    Some("--import-xdg") => {
+                        set_task(&mut task, "import-xdg", Action::ImportXdg)?
+                    }
+                    Some("--import-legacy") => {
+                        set_task(&mut task, "import-legacy", Action::ImportLegacy)?
+                    }
+                    Some("--repair") => repair = true,
+                    Some("--stats") => editor.set(Editor::Stats)?,
+                    Some("--rest") | Some("-r") => editor.set(Editor::Restore)?,
+                    Some("--del") | Some("-d") => editor.set(Editor::Delete)?,
+                    Some("--cat") => editor.set(Editor::Cat)?,
+                    Some("--extract") => {
+                        editor.set(Editor::Extract)?;
+                        match args.next() {
+                            Some(path) => extract = Some(File(path.as_ref().to_os_string())),
+                            None => return Err(Error::MissingValue("extract")),
+                        }
+                    }
+                    Some("--inside") => match args.next() {
+                        Some(path) => inside = Some(File(path.as_ref().to_os_string())),
+                        None => return Err(Error::MissingValue("inside")),
+                    },
+                    Some("--fzf") | Some("-F") => selector.add_fzf(),
+                    Some("--pat") | Some("-P") => do_take_while!(args, "pat", selector.add_pat),
+                    Some("--idx") | Some("-I") => do_take_while!(args, "idx", selector.add_idx),
+                    Some("--blk") | Some("-B") => do_take_while!(args, "blk", selector.add_blk),
+                    Some("--time") | Some("-T") => do_take_while!(args, "time", selector.add_time),
+                    Some("--sandbox") | Some("-S") => sandbox = true,
+                    Some("--overwrite") | Some("-O") => overwrite = true,
+                    Some("--to") => match args.next() {
+                        Some(path) => to = Some(File(path.as_ref().to_os_string())),
+                        None => return Err(Error::MissingValue("to")),
+                    },
+                    Some("--parents") => parents = true,
+                    Some("--json") => format = Format::Json,
+                    Some("--format") => {
+                        let fmt = args.next();
+                        format = match fmt.as_ref().and_then(|f| f.as_ref().to_str()) {
+                            Some("human") => Format::Human,
+                            Some("json") => Format::Json,
+                            Some("tsv") => Format::Tsv,
+                            _ => {
+                                let fmt = fmt.map(|f| f.as_ref().to_string_lossy().to_string());
+                                return Err(Error::InvalidFormat(fmt.unwrap_or_default()));
+                            }
+                        }
+                    }
+                    Some("--") => break,
                     _ => {
-                        if arg.as_ref().starts_with('-') {
-                            return Err(Error::UnknownArg(arg.as_ref().to_string()));
+                        if arg.as_ref().as_bytes().starts_with(b"-") {
+                            let arg = arg.as_ref().to_string_lossy().to_string();
+                            return Err(Error::UnknownArg(arg));
                         }
-                        pos_args.push(arg.as_ref().to_string());
+                        pos_args.push(arg.as_ref().to_os_string());
                     }
                 },
             }
         }
         for arg in args {
             // drain remaining args as positional (encountered '--')
-            pos_args.push(arg.as_ref().to_string());
+            pos_args.push(arg.as_ref().to_os_string());
+        }
+        let editor = editor.make_inner();
+        if let Some((name, _)) = task {
+            if help {
+                return Err(Error::NonExclusiveCmd("help", name));
+            }
+            if undo {
+                return Err(Error::NonExclusiveCmd("undo", name));
+            }
+            if let Some(ed) = editor {
+                return Err(Error::NonExclusiveCmd(ed.as_str(), name));
+            }
         }
-        let action = match (help, undo, editor.make_inner()) {
+        let action = match (help, undo, editor) {
             // Incompatibilities
             (true, true, _) => return Err(Error::NonExclusiveCmd("help", "undo")),
             (true, _, Some(ed)) => return Err(Error::NonExclusiveCmd("help", ed.as_str())),
             (_, true, Some(ed)) => return Err(Error::NonExclusiveCmd("undo", ed.as_str())),
             // Ok
-            (true, _, _) => Action::Help(pos_args.into_iter().map(Help).collect()),
+            (true, _, _) => Action::Help(
+                pos_args
+                    .into_iter()
+                    .map(|arg| Help(arg.to_string_lossy().to_string()))
+                    .collect(),
+            ),
             (_, true, _) => {
                 if !pos_args.is_empty() {
-                    return Err(Error::TooManyArgs("undo", pos_args));
+                    return Err(Error::TooManyArgs("undo", lossy(pos_args)));
                 }
                 if selector.active {
                     return Err(Error::UselessSelector("undo", selector));
@@ -507,10 +759,20 @@ impl Command {
             }
             (_, _, Some(ed)) => {
                 if !pos_args.is_empty() {
-                    return Err(Error::TooManyArgs(ed.as_str(), pos_args));
+                    return Err(Error::TooManyArgs(ed.as_str(), lossy(pos_args)));
                 }
                 Action::Edit(ed, selector)
             }
+            _ if task.is_some() => {
+                let (name, action) = task.unwrap();
+                if !pos_args.is_empty() {
+                    return Err(Error::TooManyArgs(name, lossy(pos_args)));
+                }
+                if selector.active {
+                    return Err(Error::UselessSelector(name, selector));
+                }
+                action
+            }
             _ => {
                 if pos_args.is_empty() {
                     Action::Edit(Editor::Null, selector)
@@ -522,19 +784,56 @@ impl Command {
                 }
             }
         };
-        let critical = !matches!(
-            &action,
-            Action::Edit(Editor::Null, _) | Action::Edit(Editor::Info, _) | Action::Help(_)
+        if parents && to.is_none() && extract.is_none() {
+            return Err(Error::UselessOption("parents", &["to", "extract"]));
+        }
+        if to.is_some() && !matches!(action, Action::Edit(Editor::Restore, _)) {
+            return Err(Error::UselessOption("to", &["rest"]));
+        }
+        let copying = matches!(
+            action,
+            Action::Edit(Editor::Cat, _) | Action::Edit(Editor::Extract, _)
         );
+        if inside.is_some() && !copying {
+            return Err(Error::UselessOption("inside", &["cat"]));
+        }
+        if repair && action != Action::Fsck {
+            return Err(Error::UselessOption("repair", &["fsck"]));
+        }
         Ok(Self {
             action,
             sandbox,
             overwrite,
-            critical,
+            to,
+            parents,
+            extract,
+            inside,
+            repair,
+            format,
         })
     }
 }
 
+fn set_task(
+    task: &mut Option<(&'static str, Action)>,
+    name: &'static str,
+    action: Action,
+) -> Result<(), Error> {
+    match task {
+        Some((prev, _)) => Err(Error::NonExclusiveCmd(prev, name)),
+        None => {
+            *task = Some((name, action));
+            Ok(())
+        }
+    }
+}
+
+fn lossy(args: Vec<OsString>) -> Vec<String> {
+    args.into_iter()
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect()
+}
+
 impl Selector {
     pub fn new() -> Self {
         Self::default()
@@ -565,10 +864,10 @@ impl Selector {
         self.active = true;
     }
 
-    pub fn make(self) -> Result<select::Selector, Error> {
+    pub fn make(self, cfg: &Config) -> Result<select::Selector, Error> {
         let mut sel = select::Selector::new();
         if self.fzf {
-            sel.push(select::Fzf {});
+            sel.push(select::Fzf::new(cfg.fzf_cmd()));
         }
         for b in self.blk {
             sel.push(b.make()?);
@@ -613,6 +912,7 @@ impl OnceEd {
     }
 }
 
+#[cfg(test)]
 macro_rules! assert_matches {
     ( $obj:expr, $target:pat ) => {{
         let obj = $obj;
@@ -626,6 +926,7 @@ macro_rules! assert_matches {
     }};
 }
 
+#[cfg(test)]
 mod test {
     use super::*;
     use crate::select;
@@ -637,6 +938,30 @@ mod test {
         let neither = Command::parse(&["-F", "-I", "3-"]).unwrap();
         assert!(!neither.sandbox);
         assert!(!neither.overwrite);
+        assert_eq!(neither.to, None);
+        let to = Command::parse(&["--rest", "--to", "-dir", "--parents", "-I", "1"]).unwrap();
+        assert_eq!(to.to, Some(File(OsString::from("-dir"))));
+        assert!(to.parents);
+        let undo = Command::parse(&["-u", "--to", "/tmp"]).unwrap();
+        assert_eq!(undo.to, Some(File(OsString::from("/tmp"))));
+        let extract = Command::parse(&["--extract", "out", "--inside", "src/lib.rs", "-I", "2"]).unwrap();
+        assert_matches!(extract.action, Action::Edit(Editor::Extract, _));
+        assert_eq!(extract.extract, Some(File(OsString::from("out"))));
+        assert_eq!(extract.inside, Some(File(OsString::from("src/lib.rs"))));
+        assert_eq!(neither.format, Format::Human);
+    }
+
+    #[test]
+    fn format_is_detected() {
+        let json = Command::parse(&["--json", "-I", "1"]).unwrap();
+        assert_eq!(json.format, Format::Json);
+        let tsv = Command::parse(&["--format", "tsv", "--rest", "-I", "1"]).unwrap();
+        assert_eq!(tsv.format, Format::Tsv);
+        assert_matches!(
+            Command::parse(&["--format", "xml"]),
+            Err(Error::InvalidFormat(_))
+        );
+        assert_matches!(Command::parse(&["--format"]), Err(Error::InvalidFormat(_)));
     }
 
     #[test]
@@ -650,24 +975,48 @@ mod test {
         assert_eq!(
             del.action,
             Action::Edit(
-                Some(Editor::Delete),
+                Editor::Delete,
                 Selector {
                     active: true,
                     fzf: false,
                     idx: vec![Index("3:7".to_string())],
                     pat: vec![],
+                    blk: vec![],
                     time: vec![]
                 }
             )
         );
         let undo = Command::parse(&["--undo"]).unwrap();
-        assert_eq!(undo.action, Action::Undo);
+        assert_eq!(
+            undo.action,
+            Action::Edit(
+                Editor::Restore,
+                Selector {
+                    blk: vec![Block("1".to_string())],
+                    ..Selector::default()
+                }
+            )
+        );
+        let stats = Command::parse(&["--stats"]).unwrap();
+        assert_eq!(
+            stats.action,
+            Action::Edit(Editor::Stats, Selector::default())
+        );
+        let gc = Command::parse(&["--gc", "-S"]).unwrap();
+        assert_eq!(gc.action, Action::Gc);
+        let fsck = Command::parse(&["--fsck", "--repair"]).unwrap();
+        assert_eq!(fsck.action, Action::Fsck);
+        assert!(fsck.repair);
+        let import = Command::parse(&["--import-xdg", "--json"]).unwrap();
+        assert_eq!(import.action, Action::ImportXdg);
+        let legacy = Command::parse(&["--import-legacy", "-S"]).unwrap();
+        assert_eq!(legacy.action, Action::ImportLegacy);
         let remove = Command::parse(&["foo.txt", "bar.sh"]).unwrap();
         assert_eq!(
             remove.action,
             Action::Remove(vec![
-                File("foo.txt".to_string()),
-                File("bar.sh".to_string())
+                File(OsString::from("foo.txt")),
+                File(OsString::from("bar.sh"))
             ])
         );
     }
@@ -678,7 +1027,7 @@ mod test {
         assert_eq!(
             ended.action,
             Action::Edit(
-                None,
+                Editor::Null,
                 Selector {
                     active: true,
                     fzf: false,
@@ -688,6 +1037,7 @@ mod test {
                         Index("3".to_string())
                     ],
                     pat: vec![Pattern("".to_string())],
+                    blk: vec![],
                     time: vec![],
                 }
             )
@@ -713,9 +1063,33 @@ mod test {
         let unknown = Command::parse(&["--foo"]);
         assert_matches!(unknown, Err(Error::UnknownArg(_)));
         let useless1 = Command::parse(&["-F", "--undo"]);
-        assert_matches!(useless1, Err(Error::UndoUselessSelector(_)));
+        assert_matches!(useless1, Err(Error::UselessSelector("undo", _)));
         let useless2 = Command::parse(&["foo.txt", "-I", "3"]);
-        assert_matches!(useless2, Err(Error::RemoveUselessSelector(_)));
+        assert_matches!(useless2, Err(Error::UselessSelector("remove", _)));
+        let useless3 = Command::parse(&["--gc", "-T", "1D"]);
+        assert_matches!(useless3, Err(Error::UselessSelector("gc", _)));
+        let non_exclusive4 = Command::parse(&["--del", "--gc"]);
+        assert_matches!(non_exclusive4, Err(Error::NonExclusiveCmd("del", "gc")));
+        let non_exclusive5 = Command::parse(&["--gc", "--fsck"]);
+        assert_matches!(non_exclusive5, Err(Error::NonExclusiveCmd("gc", "fsck")));
+        let useless4 = Command::parse(&["--import-xdg", "foo.txt"]);
+        assert_matches!(useless4, Err(Error::TooManyArgs("import-xdg", _)));
+        let missing = Command::parse(&["--rest", "--to"]);
+        assert_matches!(missing, Err(Error::MissingValue("to")));
+        let useless5 = Command::parse(&["--del", "--to", "/tmp"]);
+        assert_matches!(useless5, Err(Error::UselessOption("to", &["rest"])));
+        let useless6 = Command::parse(&["--rest", "--parents"]);
+        assert_matches!(useless6, Err(Error::UselessOption("parents", &["to", "extract"])));
+        let non_exclusive6 = Command::parse(&["--import-xdg", "--import-legacy"]);
+        assert_matches!(non_exclusive6, Err(Error::NonExclusiveCmd("import-xdg", "import-legacy")));
+        let missing2 = Command::parse(&["--extract"]);
+        assert_matches!(missing2, Err(Error::MissingValue("extract")));
+        let useless7 = Command::parse(&["--rest", "--inside", "src"]);
+        assert_matches!(useless7, Err(Error::UselessOption("inside", &["cat"])));
+        let non_exclusive7 = Command::parse(&["--cat", "--extract", "out"]);
+        assert_matches!(non_exclusive7, Err(Error::NonExclusiveCmd("cat", "extract")));
+        let useless8 = Command::parse(&["--gc", "--repair"]);
+        assert_matches!(useless8, Err(Error::UselessOption("repair", &["fsck"])));
     }
 
     #[test]
@@ -756,5 +1130,31 @@ mod test {
             Time("13M".to_string()).make().unwrap(),
             select::Time::new(13 * 30 * 24 * 60 * 60, 13 * 30 * 24 * 60 * 60)
         );
+        assert_eq!(
+            Time("3M4d:".to_string()).make().unwrap(),
+            select::Time::new(3 * 30 * 24 * 60 * 60 + 4 * 24 * 60 * 60, u64::MAX)
+        );
+        assert_eq!(
+            Time(":2".to_string()).make().unwrap(),
+            select::Time::new(0, 2 * 24 * 60 * 60)
+        );
+        assert_matches!(Time("3x".to_string()).make(), Err(Error::WrongDuration(_, Some('x'))));
+        assert_matches!(
+            Time::delta_time("99999999999999999999"),
+            Err(Error::WrongDuration(_, None))
+        );
+        assert_matches!(Time::delta_time("999999999999Y"), Err(Error::WrongDuration(_, None)));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("10B").unwrap(), 10);
+        assert_eq!(parse_size("3K").unwrap(), 3 * 1024);
+        assert_eq!(parse_size("500MB").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_matches!(parse_size("2X"), Err(Error::InvalidSize(_)));
+        assert_matches!(parse_size("G"), Err(Error::InvalidSize(_)));
+        assert_matches!(parse_size("99999999999T"), Err(Error::InvalidSize(_)));
     }
 }
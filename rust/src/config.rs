@@ -1,66 +1,351 @@
-use std::fs::File;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
-pub struct Config {
+/// A directory holding removed files: `history` records past transactions
+/// and `registry` stores the actual files.
+///
+/// Roots are identified by their directory alone.
+#[derive(Debug, Clone)]
+pub struct Root {
     root: PathBuf,
     history: PathBuf,
-    lock: PathBuf,
     registry: PathBuf,
-    ls_cmd: &'static str,
-    fzf_cmd: &'static str,
+}
+
+impl Root {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            history: root.join("history"),
+            registry: root.join("registry"),
+            root,
+        }
+    }
+
+    /// A FreeDesktop.org trash, where `info` takes the place of the history
+    /// and `files` that of the registry. See `xdg`.
+    pub fn xdg(trash: PathBuf) -> Self {
+        Self {
+            history: trash.join("info"),
+            registry: trash.join("files"),
+            root: trash,
+        }
+    }
+
+    /// Create the directory of a root on a filesystem shared with other
+    /// users, that only the current user can access. Whatever is already
+    /// there is only used if it passes the same check, since someone else
+    /// may have created it to receive our files.
+    fn create_private(&self) -> bool {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        match std::fs::DirBuilder::new().mode(0o700).create(&self.root) {
+            // The umask may have taken more than wanted
+            Ok(()) => {
+                let private = std::fs::Permissions::from_mode(0o700);
+                if std::fs::set_permissions(&self.root, private).is_err() {
+                    return false;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+            Err(_) => return false,
+        }
+        self.private() && self.create().is_ok()
+    }
+
+    /// Whether the directory of the root is a real directory that belongs
+    /// to the current user and that nobody else can access.
+    fn private(&self) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        let uid = unsafe { libc::getuid() };
+        match self.root.symlink_metadata() {
+            Ok(meta) => {
+                meta.file_type().is_dir() && meta.uid() == uid && meta.mode() & 0o777 == 0o700
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn create(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.registry)?;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.history)
+            .map(|_| ())
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.as_path()
+    }
+
+    pub fn history(&self) -> &Path {
+        self.history.as_path()
+    }
+
+    pub fn registry(&self) -> &Path {
+        self.registry.as_path()
+    }
+}
+
+impl PartialEq for Root {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root
+    }
+}
+
+impl Eq for Root {}
+
+impl PartialOrd for Root {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Root {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.root.cmp(&other.root)
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    main: Root,
+    mounts: Vec<Root>,
+    known_mounts: PathBuf,
+    lock: PathBuf,
+    expired: PathBuf,
+    journal: PathBuf,
+    expire: Option<u64>,
+    quota: Option<u64>,
+    strict_quota: bool,
+    xdg: bool,
 }
 
 impl Config {
-    pub fn getenv() -> Self {
-        let root = match std::env::var("REM_ROOT") {
-            Ok(s) => PathBuf::from(s),
-            Err(_) => match std::env::var("HOME") {
-                Ok(s) => {
+    /// Configuration given by the environment, with the trash in
+    /// `$REM_ROOT` or else `$HOME/._trash`, which is created if needed.
+    pub fn getenv() -> Result<Self, crate::command::Error> {
+        let root = match std::env::var_os("REM_ROOT") {
+            Some(s) => PathBuf::from(s),
+            None => match std::env::var_os("HOME") {
+                Some(s) => {
                     let mut path = PathBuf::from(s);
                     path.push("._trash");
                     path
                 }
-                Err(_) => PathBuf::from("/tmp/trash"),
+                None => PathBuf::from("/tmp/trash"),
             },
         };
-        std::fs::create_dir_all(&root).unwrap();
-        let root = root.canonicalize().unwrap();
-        let ls_cmd = get_ls_cmd();
-        let fzf_cmd = get_fzf_cmd();
-        let mut cfg = Config {
-            history: root.clone(),
-            lock: root.clone(),
-            registry: root.clone(),
-            root,
-            ls_cmd,
-            fzf_cmd,
+        let expire = max_age(std::env::var("REM_EXPIRE").ok().as_deref());
+        let quota = match std::env::var("REM_QUOTA").ok().as_deref() {
+            None | Some("") => None,
+            Some(size) => match crate::command::parse_size(size) {
+                Ok(bytes) => Some(bytes),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    None
+                }
+            },
         };
-        cfg.history.push("history");
-        cfg.lock.push("lock");
-        cfg.registry.push("registry");
-        std::fs::create_dir_all(&cfg.registry).unwrap();
-        cfg
+        let strict = !matches!(
+            std::env::var("REM_QUOTA_STRICT").ok().as_deref(),
+            None | Some("") | Some("0")
+        );
+        let xdg = !matches!(
+            std::env::var("REM_XDG").ok().as_deref(),
+            None | Some("") | Some("0")
+        );
+        let cfg = Self::new(root.clone()).map_err(|_| {
+            crate::command::Error::CouldNotCreateDir(root.to_string_lossy().to_string())
+        })?;
+        Ok(cfg
+            .with_expire(expire)
+            .with_quota(quota, strict)
+            .with_xdg(if xdg { crate::xdg::home_trash() } else { None }))
+    }
+
+    /// Use the trash stored in `root`, creating it if needed.
+    pub fn new(root: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&root)?;
+        let root = root.canonicalize()?;
+        let main = Root::new(root.clone());
+        main.create()?;
+        let known_mounts = root.join("mounts");
+        // Per-filesystem roots whose device is not currently mounted are
+        // simply ignored until it is.
+        let mounts = std::fs::read(&known_mounts)
+            .unwrap_or_default()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| Root::new(PathBuf::from(OsStr::from_bytes(line))))
+            .filter(|mount| mount.private())
+            .filter(|mount| mount.history().is_file() && mount.registry().is_dir())
+            .collect();
+        Ok(Config {
+            main,
+            mounts,
+            known_mounts,
+            lock: root.join("lock"),
+            expired: root.join("expired"),
+            journal: root.join("journal"),
+            expire: None,
+            quota: None,
+            strict_quota: false,
+            xdg: false,
+        })
+    }
+
+    /// Configuration for a trash in `root` that is never created on disk,
+    /// for use with a storage that does not live in the filesystem.
+    pub fn detached(root: PathBuf) -> Self {
+        Config {
+            main: Root::new(root.clone()),
+            mounts: Vec::new(),
+            known_mounts: root.join("mounts"),
+            lock: root.join("lock"),
+            expired: root.join("expired"),
+            journal: root.join("journal"),
+            expire: None,
+            quota: None,
+            strict_quota: false,
+            xdg: false,
+        }
+    }
+
+    /// Entries older than `expire` seconds are purged automatically.
+    pub fn with_expire(mut self, expire: Option<u64>) -> Self {
+        self.expire = expire;
+        self
+    }
+
+    pub fn expire(&self) -> Option<u64> {
+        self.expire
+    }
+
+    /// Maximum size of the trash in bytes. Removals that would exceed it
+    /// evict the oldest batches, or are refused if `strict` is set.
+    pub fn with_quota(mut self, quota: Option<u64>, strict: bool) -> Self {
+        self.quota = quota;
+        self.strict_quota = strict;
+        self
+    }
+
+    /// Keep removed files in the FreeDesktop.org trash `trash` instead of
+    /// the roots of `rem`, which only holds the lock and the logs then.
+    pub fn with_xdg(mut self, trash: Option<PathBuf>) -> Self {
+        if let Some(trash) = trash {
+            self.main = Root::xdg(trash);
+            self.mounts.clear();
+            self.xdg = true;
+        }
+        self
+    }
+
+    /// Whether the roots are FreeDesktop.org trashes, see `xdg`.
+    pub fn xdg(&self) -> bool {
+        self.xdg
+    }
+
+    pub fn quota(&self) -> Option<u64> {
+        self.quota
+    }
+
+    pub fn strict_quota(&self) -> bool {
+        self.strict_quota
+    }
+
+    /// Operations in progress, see `journal`.
+    pub fn journal(&self) -> &Path {
+        self.journal.as_path()
+    }
+
+    /// Log of the entries purged because they expired.
+    pub fn expired(&self) -> &Path {
+        self.expired.as_path()
     }
 
     pub fn root(&self) -> &Path {
-        self.root.as_path()
+        self.main.root()
     }
 
     pub fn history(&self) -> &Path {
-        self.history.as_path()
+        self.main.history()
+    }
+
+    pub fn lock(&self) -> &Path {
+        self.lock.as_path()
     }
 
     pub fn registry(&self) -> &Path {
-        self.registry.as_path()
+        self.main.registry()
     }
 
-    pub fn fzf_cmd(&self) -> &'static str {
-        self.fzf_cmd
+    /// All known roots, starting with the main one.
+    pub fn roots(&self) -> impl Iterator<Item = &Root> {
+        std::iter::once(&self.main).chain(self.mounts.iter())
     }
 
-    pub fn ls_cmd(&self) -> &'static str {
-        self.ls_cmd
+    /// Choose the root in which to store `file`.
+    ///
+    /// Files that are not on the same filesystem as the main root go to
+    /// `<mountpoint>/.rem-trash-$UID` so that removal remains a simple rename,
+    /// falling back to the main root if that directory cannot be created
+    /// or is not private to the current user.
+    ///
+    /// A FreeDesktop.org trash takes everything, moving across filesystems
+    /// if needed. In `sandbox` mode the root that would be created is not.
+    pub fn root_for(&mut self, file: &Path, sandbox: bool) -> Root {
+        use std::os::unix::fs::MetadataExt;
+        if self.xdg {
+            return self.main.clone();
+        }
+        let device = |p: &Path| p.symlink_metadata().map(|m| m.dev()).ok();
+        let parent = file.parent().unwrap_or(file);
+        // A detached main root is not on any filesystem either
+        let dev = match (device(parent), device(self.root())) {
+            (Some(dev), Some(main)) if dev != main => dev,
+            _ => return self.main.clone(),
+        };
+        let mut mountpoint = parent;
+        while let Some(up) = mountpoint.parent() {
+            if device(up) != Some(dev) {
+                break;
+            }
+            mountpoint = up;
+        }
+        let uid = unsafe { libc::getuid() };
+        let candidate = Root::new(mountpoint.join(format!(".rem-trash-{}", uid)));
+        if let Some(known) = self.mounts.iter().find(|m| **m == candidate) {
+            return known.clone();
+        }
+        if sandbox {
+            return candidate;
+        }
+        if !candidate.create_private() || device(candidate.root()) != Some(dev) {
+            return self.main.clone();
+        }
+        use std::io::Write;
+        let mut line = candidate.root().as_os_str().as_bytes().to_vec();
+        line.push(b'\n');
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.known_mounts)
+            .and_then(|mut f| f.write_all(&line))
+            .unwrap_or_else(|_| {
+                let err = crate::command::Error::FailedToWrite(
+                    self.known_mounts.to_string_lossy().to_string(),
+                    Some(candidate.root().to_string_lossy().to_string()),
+                );
+                eprintln!("{}", err)
+            });
+        self.mounts.push(candidate.clone());
+        candidate
+    }
+
+    /// Looked up only when needed, since it requires probing `$PATH`.
+    pub fn fzf_cmd(&self) -> &'static str {
+        get_fzf_cmd()
     }
 }
 
@@ -71,27 +356,6 @@ fn cmd_exists(cmd: &str) -> bool {
         .is_ok()
 }
 
-fn default_ls_cmd() -> &'static str {
-    if cmd_exists("exa") {
-        "exa"
-    } else {
-        "ls"
-    }
-}
-
-fn get_ls_cmd() -> &'static str {
-    match std::env::var("REM_LS").ok().as_deref() {
-        Some("ls") => "ls",
-        Some("exa") if cmd_exists("exa") => "exa",
-        Some(other) => {
-            let err = crate::command::Error::InvalidVarLs(other.to_string());
-            eprintln!("{}", err);
-            default_ls_cmd()
-        }
-        None => default_ls_cmd(),
-    }
-}
-
 fn default_fzf_cmd() -> &'static str {
     if cmd_exists("sk") {
         "sk"
@@ -116,3 +380,50 @@ fn get_fzf_cmd() -> &'static str {
         None => default_fzf_cmd(),
     }
 }
+
+/// Value of `$REM_EXPIRE` in seconds. Like other variables, a zero turns
+/// expiry off rather than purging everything.
+fn max_age(var: Option<&str>) -> Option<u64> {
+    match crate::command::Time::delta_time(var?) {
+        Ok(0) => None,
+        Ok(secs) => Some(secs),
+        Err(err) => {
+            eprintln!("{}", err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn expire_var() {
+        assert_eq!(max_age(Some("30D")), Some(30 * 24 * 60 * 60));
+        assert_eq!(max_age(Some("1h")), Some(60 * 60));
+        for off in &[None, Some(""), Some("0"), Some("0D"), Some("3x")] {
+            assert_eq!(max_age(*off), None);
+        }
+    }
+
+    #[test]
+    fn private_roots() {
+        let dir = TempDir::new("config");
+        let mine = Root::new(dir.join("mine"));
+        assert!(mine.create_private());
+        assert!(mine.private() && mine.registry().is_dir());
+        // Already there, and still usable
+        assert!(mine.create_private());
+        // Created by someone who can let others in
+        let open = Root::new(dir.join("open"));
+        std::fs::create_dir(open.root()).unwrap();
+        std::fs::set_permissions(open.root(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(!open.create_private());
+        let link = Root::new(dir.join("link"));
+        std::os::unix::fs::symlink(mine.root(), link.root()).unwrap();
+        assert!(!link.create_private());
+    }
+}
@@ -1,65 +1,195 @@
-use crate::{
-    command::{self, Action, Command, Error},
+use rem::{
+    cli::{Action, Command, Editor, Outcome, Record, Report},
     config::Config,
-    select::{Entry, Select},
+    select::{Entries, Entry, Index, Select, Selection},
+    trash::{RestoreOptions, Results, Trash},
+    Error, Storage, XdgStorage,
 };
-use std::fmt;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
 
-pub fn exec(cmd: Command, cfg: Config) {
+pub fn exec(cmd: Command) {
+    let cfg = match Config::getenv() {
+        Ok(cfg) => cfg,
+        Err(err) => return Report::new(cmd.format).error(&err),
+    };
+    if cfg.xdg() {
+        run(cmd, Trash::with_storage(cfg, XdgStorage))
+    } else {
+        run(cmd, Trash::new(cfg))
+    }
+}
+
+fn run<S: Storage>(cmd: Command, trash: Trash<S>) {
+    let report = Report::new(cmd.format);
+    let mut trash = trash.sandbox(cmd.sandbox).plans_on_stderr(!report.human());
+    // Only before actions that change the trash anyway
+    let opportunistic = match &cmd.action {
+        Action::Remove(_) | Action::ImportXdg | Action::ImportLegacy => !cmd.sandbox,
+        Action::Edit(Editor::Delete, _) | Action::Edit(Editor::Restore, _) => !cmd.sandbox,
+        _ => false,
+    };
+    if !matches!(cmd.action, Action::Help(_)) {
+        if let Err(err) = trash.recover() {
+            report.error(&err);
+        }
+    }
+    if let (true, Some(age)) = (opportunistic, trash.config().expire()) {
+        // Only the log keeps track of what was purged this way
+        let expired = trash.expire(age);
+        for err in expired.items.iter().filter_map(|res| res.as_ref().err()) {
+            report.error(err);
+        }
+        // Whoever holds the trash will not mind if expiry waits a bit more
+        for err in &expired.errors {
+            if !matches!(err, Error::Locked(..)) {
+                report.error(err);
+            }
+        }
+    }
     match cmd.action {
         Action::Remove(files) => {
-            let mut register = Vec::new();
-            for f in files {
-                match remove(&cfg, cmd.sandbox, f) {
-                    Ok(entry) => register.push(entry),
-                    Err(Error::SandBoxed) => (),
-                    Err(err) => eprintln!("{}", err),
+            let paths = files.iter().map(|f| f.make()).collect::<Vec<_>>();
+            let removal = trash.remove(&paths);
+            for res in &removal.evicted.items {
+                match res {
+                    Ok(_) if report.human() && cmd.sandbox => (),
+                    Ok(entry) if report.human() => {
+                        println!("Evicted {} to stay within the quota", entry.true_name())
+                    }
+                    Ok(entry) => report.record(&Record::of(entry, None).outcome(if cmd.sandbox {
+                        Outcome::Planned
+                    } else {
+                        Outcome::Evicted
+                    })),
+                    Err(err) => report.error(err),
                 }
             }
-            use std::io::Write;
-            let mut history = std::fs::OpenOptions::new()
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(cfg.history())
-                .unwrap();
-            writeln!(history, "").unwrap_or_else(|_| {
-                let err = Error::FailedToWrite(
-                    cfg.registry().to_str().unwrap().to_string(),
-                    Some("".to_string()),
-                );
-                eprintln!("{}", err)
-            });
-            for entry in register {
-                let contents = format!("{}|{}|{}", entry.alias, entry.name, entry.timestamp);
-                writeln!(history, "{}", contents).unwrap_or_else(|_| {
-                    let err = Error::FailedToWrite(
-                        cfg.registry().to_str().unwrap().to_string(),
-                        Some(contents),
-                    );
-                    eprintln!("{}", err)
-                });
+            errors(report, &removal.evicted);
+            let removed = removal.removed;
+            // The new batch is the most recent one
+            let outcome = if cmd.sandbox {
+                Outcome::Planned
+            } else {
+                Outcome::Removed
+            };
+            for (path, res) in paths.into_iter().zip(&removed.items) {
+                match res {
+                    Ok(entry) => {
+                        let meta = trash.meta(entry);
+                        let mut rec = Record::of(entry, meta.as_ref()).outcome(outcome);
+                        if !cmd.sandbox {
+                            rec.block = Some(1);
+                        }
+                        report.record(&rec);
+                    }
+                    Err(err) => {
+                        if report.human() {
+                            eprintln!("{}", err);
+                        }
+                        let rec = Record {
+                            path,
+                            ..Record::default()
+                        };
+                        report.record(&rec.failed(err));
+                    }
+                }
             }
+            errors(report, &removed);
         }
         Action::Edit(ed, sel) => {
-            let entries = match crate::select::Entries::from_file(cfg.history()) {
+            let entries = match trash.entries() {
                 Ok(entries) => entries,
                 Err(e) => {
-                    eprintln!("{}", e);
+                    report.error(&e);
                     return;
                 }
             };
-            let mut selection = std::collections::BTreeSet::new();
-            match sel.make() {
-                Err(e) => eprintln!("{}", e),
+            let mut selection = Selection::new();
+            match sel.make(trash.config()) {
+                Err(e) => report.error(&e),
                 Ok(sel) => {
                     sel.select(&entries, &mut selection);
-                    ed.run(&cfg, &entries, &selection);
+                    match ed {
+                        Editor::Delete => {
+                            delete(&mut trash, cmd.sandbox, report, &entries, &selection)
+                        }
+                        Editor::Restore => {
+                            let options = RestoreOptions {
+                                overwrite: cmd.overwrite,
+                                to: cmd.to.as_ref().map(|to| to.make()),
+                                parents: cmd.parents,
+                            };
+                            restore(
+                                &mut trash,
+                                cmd.sandbox,
+                                options,
+                                report,
+                                &entries,
+                                &selection,
+                            )
+                        }
+                        Editor::Info => info(&trash, report, &entries, &selection),
+                        Editor::Stats => {
+                            // Statistics are about the whole trash unless told otherwise
+                            if sel.is_empty() {
+                                Index::new(1, usize::MAX).select(&entries, &mut selection);
+                            }
+                            report.stats(&trash.stats(&entries, &selection))
+                        }
+                        Editor::Cat => {
+                            let inside = cmd.inside.as_ref().map(|inside| inside.make());
+                            let out = std::io::stdout();
+                            let printed = trash.cat(&selection, inside.as_deref(), &mut out.lock());
+                            for err in printed.items.iter().filter_map(|res| res.as_ref().err()) {
+                                report.error(err);
+                            }
+                        }
+                        Editor::Extract => {
+                            let options = RestoreOptions {
+                                overwrite: cmd.overwrite,
+                                to: cmd.extract.as_ref().map(|dest| dest.make()),
+                                parents: cmd.parents,
+                            };
+                            let inside = cmd.inside.as_ref().map(|inside| inside.make());
+                            extract(
+                                &mut trash,
+                                cmd.sandbox,
+                                options,
+                                inside.as_deref(),
+                                report,
+                                &entries,
+                                &selection,
+                            )
+                        }
+                        Editor::Null => list(&trash, report, &entries, &selection),
+                    }
                 }
             }
         }
+        Action::Gc => match trash.config().expire() {
+            Some(age) => gc(&mut trash, cmd.sandbox, report, age),
+            None => report.error(&Error::NoExpiry),
+        },
+        Action::Fsck => fsck(&mut trash, cmd.sandbox, cmd.repair, report),
+        Action::ImportXdg => match rem::cli::xdg_trash() {
+            Some(dir) => {
+                let imported = trash.import_xdg(&dir);
+                import(&trash, cmd.sandbox, report, &dir, imported)
+            }
+            None => report.error(&Error::FileDoesNotExist(String::from(
+                "$XDG_DATA_HOME/Trash",
+            ))),
+        },
+        Action::ImportLegacy => match rem::cli::legacy_root() {
+            Some(dir) if rem::cli::is_legacy_trash(&dir) => {
+                let imported = trash.import_legacy(&dir);
+                import(&trash, cmd.sandbox, report, &dir, imported)
+            }
+            dir => {
+                let dir = dir.unwrap_or_else(|| PathBuf::from("$HOME/.trash"));
+                report.error(&Error::NoLegacyTrash(dir.to_string_lossy().to_string()))
+            }
+        },
         Action::Help(menus) => {
             if menus.is_empty() {
                 println!("{}", MSG_HELP_MAIN);
@@ -80,6 +210,12 @@ pub fn exec(cmd: Command, cfg: Config) {
                             "fzf" => MSG_HELP_FZF,
                             "blk" => MSG_HELP_BLK,
                             "time" => MSG_HELP_TIME,
+                            "format" => MSG_HELP_FORMAT,
+                            "gc" => MSG_HELP_GC,
+                            "stats" => MSG_HELP_STATS,
+                            "fsck" => MSG_HELP_FSCK,
+                            "import" => MSG_HELP_IMPORT,
+                            "cat" => MSG_HELP_CAT,
                             "intro" => MSG_HELP_INTRO,
                             "config" => MSG_HELP_CONFIG,
                             other => {
@@ -94,102 +230,267 @@ pub fn exec(cmd: Command, cfg: Config) {
     }
 }
 
-fn remove(cfg: &Config, sandbox: bool, file: command::File) -> Result<Entry, Error> {
-    let mut path = std::env::current_dir().unwrap();
-    path.push(file.make());
-    let path = match path.canonicalize() {
-        Ok(name) => path,
-        Err(_) => return Err(Error::FileDoesNotExist(file.contents())),
-    };
-    let randname = generate_random_dirname();
-    let alias = {
-        let mut p = PathBuf::new();
-        p.push(&randname);
-        p
+fn errors<T>(report: Report, results: &Results<T>) {
+    for err in &results.errors {
+        report.error(err);
+    }
+}
+
+fn record<S: Storage>(trash: &Trash<S>, entries: &Entries, idx: usize, entry: &Entry) -> Record {
+    let meta = trash.meta(entry);
+    Record::of(entry, meta.as_ref()).at(idx, entries.block_of(idx))
+}
+
+fn records<S: Storage>(trash: &Trash<S>, entries: &Entries, selection: &Selection) -> Vec<Record> {
+    selection
+        .iter()
+        .map(|(idx, entry)| record(trash, entries, *idx, entry))
+        .collect()
+}
+
+fn list<S: Storage>(trash: &Trash<S>, report: Report, entries: &Entries, selection: &Selection) {
+    for (num, entry) in selection {
+        if report.human() {
+            println!("{} {}", num, entry.true_name());
+        } else {
+            report.record(&record(trash, entries, *num, entry));
+        }
+    }
+}
+
+fn info<S: Storage>(trash: &Trash<S>, report: Report, entries: &Entries, selection: &Selection) {
+    for (idx, entry) in selection {
+        if !report.human() {
+            let rec = record(trash, entries, *idx, entry).with_meta(trash.meta(entry));
+            report.record(&rec);
+            continue;
+        }
+        match trash.info(entry) {
+            Ok(text) => println!("{}", text),
+            Err(err) => report.error(&err),
+        }
+    }
+}
+
+fn delete<S: Storage>(
+    trash: &mut Trash<S>,
+    sandbox: bool,
+    report: Report,
+    entries: &Entries,
+    selection: &Selection,
+) {
+    if selection.is_empty() {
+        return;
+    }
+    // Keep stdout for the records when they are requested
+    let list = |line: String| {
+        if report.human() {
+            println!("{}", line)
+        } else {
+            eprintln!("{}", line)
+        }
     };
-    let timestamp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    let mut destination = cfg.registry().to_path_buf();
-    destination.push(&alias);
-    let mut destfile = destination.clone();
-    let mut destdata = destination.clone();
-    destfile.push(Path::new("file"));
-    destdata.push(Path::new("meta"));
-    if sandbox {
-        println!("Create directory '{}'", destination.to_str().unwrap());
-        println!("Register data as '{}'", destdata.to_str().unwrap());
-        println!(
-            "Move '{}' to '{}'",
-            path.to_str().unwrap(),
-            destfile.to_str().unwrap()
-        );
-        //println!("Save '{}|{}|{}' into '{}'", alias.to_str().unwrap(), path.to_str().unwrap(), timestamp, destdata.to_str().unwrap());
-    } else {
-        std::fs::create_dir(&destination).or_else(|_| {
-            Err(Error::CouldNotCreateDir(
-                destination.to_str().unwrap().to_string(),
-            ))
-        })?;
-        record_data(cfg, &path, &destdata)?;
-        std::fs::rename(&path, &destfile).or_else(|_| {
-            std::fs::remove_dir_all(&destination).unwrap();
-            Err(Error::CouldNotMove(
-                path.to_str().unwrap().to_string(),
-                destfile.to_str().unwrap().to_string(),
-            ))
-        })?;
-    }
-    Ok(Entry {
-        name: path.to_str().unwrap().to_string(),
-        alias: randname,
-        timestamp,
-    })
+    list("This action will _permanently_ delete".to_string());
+    for (num, entry) in selection {
+        list(format!("    {} {}", num, entry.true_name()));
+    }
+    if !sandbox && !confirm(report) {
+        list("Aborted".to_string());
+        for (idx, entry) in selection {
+            report.record(&record(trash, entries, *idx, entry).outcome(Outcome::Aborted));
+        }
+        return;
+    }
+    // Recorded data disappears along with the entries
+    let recs = records(trash, entries, selection);
+    let purged = trash.purge(selection);
+    for (rec, res) in recs.into_iter().zip(&purged.items) {
+        match res {
+            Ok(()) if sandbox => report.record(&rec.outcome(Outcome::Planned)),
+            Ok(()) => report.record(&rec.outcome(Outcome::Deleted)),
+            Err(err) => {
+                if report.human() {
+                    eprintln!("{}", err);
+                }
+                report.record(&rec.failed(err));
+            }
+        }
+    }
+    errors(report, &purged);
 }
 
-fn record_data(cfg: &Config, file: &Path, meta: &Path) -> Result<(), Error> {
-    let mut f = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(meta)
-        .unwrap();
-    let date_out = std::process::Command::new("date")
-        .arg("+%Y-%m-%d %H:%M:%S")
-        .output()
-        .or_else(|_| Err(Error::ExecError("date")))?;
-    let ls_out = std::process::Command::new(cfg.ls_cmd())
-        .arg("-Flah")
-        .arg("--color=always")
-        .arg(file.to_str().unwrap())
-        .output()
-        .or_else(|_| Err(Error::ExecError(cfg.ls_cmd())))?;
-    let file_out = std::process::Command::new("file")
-        .arg(file.to_str().unwrap())
-        .output()
-        .or_else(|_| Err(Error::ExecError("file")))?;
-    use std::io::Write;
-    writeln!(f, "{}", file.to_str().unwrap())
-        .and_then(|_| writeln!(f, "{}", std::str::from_utf8(&date_out.stdout).unwrap()))
-        .and_then(|_| writeln!(f, "\n{}", std::str::from_utf8(&ls_out.stdout).unwrap()))
-        .and_then(|_| writeln!(f, "\n{}", std::str::from_utf8(&file_out.stdout).unwrap()))
-        .or_else(|_| {
-            Err(Error::FailedToWrite(
-                file.to_str().unwrap().to_string(),
-                None,
-            ))
-        })
+fn restore<S: Storage>(
+    trash: &mut Trash<S>,
+    sandbox: bool,
+    options: RestoreOptions,
+    report: Report,
+    entries: &Entries,
+    selection: &Selection,
+) {
+    let recs = records(trash, entries, selection);
+    // Decided before anything is restored there
+    let wanted = selection
+        .iter()
+        .map(|(_, entry)| trash.destination(entry, &options, selection.len()).ok())
+        .collect::<Vec<_>>();
+    let restored = trash.restore(selection, options);
+    let outcomes = selection.iter().zip(wanted).zip(recs).zip(&restored.items);
+    for ((((_, entry), wanted), rec), res) in outcomes {
+        match res {
+            Ok(dest) => {
+                match wanted {
+                    Some(wanted) if wanted != *dest && report.human() => println!(
+                        "File '{}' already exists, using '{}' instead",
+                        wanted.display(),
+                        dest.display()
+                    ),
+                    _ => (),
+                }
+                let mut rec = rec.outcome(if sandbox {
+                    Outcome::Planned
+                } else {
+                    Outcome::Restored
+                });
+                if *dest != entry.name {
+                    rec.dest = Some(dest.clone());
+                }
+                report.record(&rec);
+            }
+            Err(err) => {
+                if report.human() {
+                    eprintln!("{}", err);
+                }
+                report.record(&rec.failed(err));
+            }
+        }
+    }
+    errors(report, &restored);
 }
 
-const ALIAS_LENGTH: usize = 25;
+fn extract<S: Storage>(
+    trash: &mut Trash<S>,
+    sandbox: bool,
+    options: RestoreOptions,
+    inside: Option<&Path>,
+    report: Report,
+    entries: &Entries,
+    selection: &Selection,
+) {
+    let recs = records(trash, entries, selection);
+    let extracted = trash.extract(selection, inside, &options);
+    for (rec, res) in recs.into_iter().zip(&extracted.items) {
+        match res {
+            Ok(_) if sandbox => report.record(&rec.outcome(Outcome::Planned)),
+            Ok(copy) => {
+                if report.human() {
+                    let src = inside.map_or(rec.path.clone(), |inside| rec.path.join(inside));
+                    println!("Extracted '{}' to '{}'", src.display(), copy.display());
+                }
+                let mut rec = rec.outcome(Outcome::Extracted);
+                rec.dest = Some(copy.clone());
+                report.record(&rec);
+            }
+            Err(err) => {
+                if report.human() {
+                    eprintln!("{}", err);
+                }
+                report.record(&rec.failed(err));
+            }
+        }
+    }
+    errors(report, &extracted);
+}
 
-fn generate_random_dirname() -> String {
-    use rand::{distributions::Alphanumeric, Rng};
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(ALIAS_LENGTH)
-        .map(char::from)
-        .collect()
+fn gc<S: Storage>(trash: &mut Trash<S>, sandbox: bool, report: Report, age: u64) {
+    let expired = trash.expire(age);
+    for res in &expired.items {
+        match res {
+            Ok(entry) => {
+                if report.human() {
+                    if !sandbox {
+                        println!("Expired {}", entry.true_name());
+                    }
+                } else {
+                    let rec = Record::of(entry, None).outcome(if sandbox {
+                        Outcome::Planned
+                    } else {
+                        Outcome::Deleted
+                    });
+                    report.record(&rec);
+                }
+            }
+            Err(err) => report.error(err),
+        }
+    }
+    errors(report, &expired);
+}
+
+fn fsck<S: Storage>(trash: &mut Trash<S>, sandbox: bool, repair: bool, report: Report) {
+    let checked = trash.fsck(repair);
+    let repaired = repair && !sandbox;
+    let mut found = 0;
+    for issue in checked.items.iter().filter_map(|res| res.as_ref().ok()) {
+        found += 1;
+        if report.human() {
+            println!("{}", issue);
+        } else {
+            report.issue(issue, repaired && checked.errors.is_empty());
+        }
+    }
+    errors(report, &checked);
+    if !report.human() || !checked.errors.is_empty() {
+        return;
+    }
+    match found {
+        0 => println!("No problems found"),
+        n if repaired => println!("Repaired {} problems", n),
+        _ if repair => (),
+        n => println!("{} problems found, use '--repair' to fix them", n),
+    }
+}
+
+fn import<S: Storage>(
+    trash: &Trash<S>,
+    sandbox: bool,
+    report: Report,
+    dir: &Path,
+    imported: Results<Entry>,
+) {
+    let outcome = if sandbox {
+        Outcome::Planned
+    } else {
+        Outcome::Imported
+    };
+    let mut count = 0;
+    for res in &imported.items {
+        match res {
+            Ok(entry) => {
+                count += 1;
+                let meta = trash.meta(entry);
+                report.record(&Record::of(entry, meta.as_ref()).outcome(outcome));
+            }
+            Err(err) => report.error(err),
+        }
+    }
+    errors(report, &imported);
+    if report.human() && !sandbox && imported.errors.is_empty() {
+        println!("Imported {} entries from '{}'", count, dir.display());
+    }
+}
+
+fn confirm(report: Report) -> bool {
+    use std::io::Write;
+    if report.human() {
+        print!("Continue ? (y/N) ");
+        std::io::stdout().flush().unwrap();
+    } else {
+        eprint!("Continue ? (y/N) ");
+    }
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y"),
+        Err(_) => false,
+    }
 }
 
 const MSG_HELP_MAIN: &str = include_str!("../../help/main.ansi");
@@ -204,5 +505,11 @@ const MSG_HELP_PAT: &str = include_str!("../../help/pat.ansi");
 const MSG_HELP_FZF: &str = include_str!("../../help/fzf.ansi");
 const MSG_HELP_BLK: &str = include_str!("../../help/blk.ansi");
 const MSG_HELP_TIME: &str = include_str!("../../help/time.ansi");
+const MSG_HELP_IMPORT: &str = include_str!("../../help/import.ansi");
+const MSG_HELP_CAT: &str = include_str!("../../help/cat.ansi");
+const MSG_HELP_FSCK: &str = include_str!("../../help/fsck.ansi");
+const MSG_HELP_STATS: &str = include_str!("../../help/stats.ansi");
+const MSG_HELP_GC: &str = include_str!("../../help/gc.ansi");
+const MSG_HELP_FORMAT: &str = include_str!("../../help/format.ansi");
 const MSG_HELP_INTRO: &str = include_str!("../../help/intro.ansi");
 const MSG_HELP_CONFIG: &str = include_str!("../../help/config.ansi");
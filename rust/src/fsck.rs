@@ -0,0 +1,280 @@
+//! Consistency between the history of a root and what its registry holds,
+//! for `--fsck`.
+//!
+//! An interrupted removal can leave data in the registry that the history
+//! does not mention, and manual cleanups can leave records of data that is
+//! gone. Repairing keeps everything that can still be restored: unknown
+//! data is adopted into a new batch, and records that cannot be used are
+//! dropped.
+
+use crate::config::Root;
+use crate::meta::Meta;
+use crate::select::Entry;
+use crate::storage::Storage;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// Stored data that the history does not mention
+    Orphan,
+    /// Stored data without the file itself
+    Incomplete,
+    /// Record of data that is not stored
+    Dangling,
+    /// Unreadable line of the history
+    Corrupted,
+    /// Record of data that is already recorded
+    Duplicate,
+}
+
+impl Problem {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Problem::Orphan => "orphan",
+            Problem::Incomplete => "incomplete",
+            Problem::Dangling => "dangling",
+            Problem::Corrupted => "corrupted",
+            Problem::Duplicate => "duplicate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub problem: Problem,
+    pub root: Root,
+    pub alias: Option<String>,
+    /// Original location of the file, if known
+    pub name: Option<PathBuf>,
+    /// Line of the history, for corrupted ones
+    pub line: Option<usize>,
+    pub detail: Option<String>,
+}
+
+impl Issue {
+    fn new(problem: Problem, root: &Root) -> Self {
+        Self {
+            problem,
+            root: root.clone(),
+            alias: None,
+            name: None,
+            line: None,
+            detail: None,
+        }
+    }
+
+    fn of(problem: Problem, entry: &Entry) -> Self {
+        Self {
+            alias: Some(entry.alias.clone()),
+            name: Some(entry.name.clone()),
+            ..Self::new(problem, &entry.root)
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alias = self.alias.as_deref().unwrap_or_default();
+        let stored = self.root.registry().join(alias);
+        let history = self.root.history().display();
+        let name = self
+            .name
+            .as_ref()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        match self.problem {
+            Problem::Orphan => {
+                write!(f, "Orphan '{}' is not in the history", stored.display())?;
+                if self.name.is_some() {
+                    write!(f, " (removed from '{}')", name)?;
+                }
+                Ok(())
+            }
+            Problem::Incomplete => write!(f, "Incomplete '{}' has no file", stored.display()),
+            Problem::Dangling => write!(
+                f,
+                "Dangling record of '{}' in '{}': '{}' is not stored",
+                name, history, alias
+            ),
+            Problem::Corrupted => write!(
+                f,
+                "Corrupted line {} of '{}': {}",
+                self.line.unwrap_or_default(),
+                history,
+                self.detail.as_deref().unwrap_or_default()
+            ),
+            Problem::Duplicate => write!(
+                f,
+                "Duplicate record of '{}' in '{}': '{}' is already recorded",
+                name, history, alias
+            ),
+        }
+    }
+}
+
+/// What was found in a root, and what it should contain once repaired.
+#[derive(Debug, Default)]
+pub struct Check {
+    pub issues: Vec<Issue>,
+    /// Usable batches of the history, oldest first
+    pub kept: Vec<Vec<Entry>>,
+    /// Orphans that can be restored, to be recorded as a new batch
+    pub adopted: Vec<Entry>,
+    /// Aliases whose data is useless and should be deleted
+    pub discarded: Vec<String>,
+}
+
+impl Check {
+    /// Reconcile the history of `root` with its registry. Orphans whose
+    /// original location was not recorded are adopted as if they had been
+    /// removed from `recovered/` inside the root, `now`.
+    pub fn run<S: Storage + ?Sized>(
+        storage: &S,
+        root: &Root,
+        now: u64,
+    ) -> Result<Self, crate::command::Error> {
+        let mut check = Self::default();
+        let contents = storage.read_history(root)?;
+        let (blocks, corrupted) = crate::history::parse_lenient(&contents, root)?;
+        for (line, err) in corrupted {
+            check.issues.push(Issue {
+                line: Some(line),
+                detail: err
+                    .parts()
+                    .map(|(title, message, _)| format!("{}, {}", title.to_lowercase(), message)),
+                ..Issue::new(Problem::Corrupted, root)
+            });
+        }
+        let mut seen = BTreeSet::new();
+        for batch in blocks {
+            let mut kept = Vec::new();
+            for entry in batch {
+                if seen.contains(&entry.alias) {
+                    check.issues.push(Issue::of(Problem::Duplicate, &entry));
+                } else if !storage.intact(root, &entry.alias) {
+                    check.issues.push(Issue::of(Problem::Dangling, &entry));
+                    if storage.contains(root, &entry.alias) {
+                        check.discarded.push(entry.alias.clone());
+                    }
+                    seen.insert(entry.alias.clone());
+                } else {
+                    seen.insert(entry.alias.clone());
+                    kept.push(entry);
+                }
+            }
+            if !kept.is_empty() {
+                check.kept.push(kept);
+            }
+        }
+        for alias in storage.aliases(root) {
+            if seen.contains(&alias) {
+                continue;
+            }
+            if !storage.intact(root, &alias) {
+                check.issues.push(Issue {
+                    alias: Some(alias.clone()),
+                    ..Issue::new(Problem::Incomplete, root)
+                });
+                check.discarded.push(alias);
+                continue;
+            }
+            let meta = storage
+                .meta(root, &alias)
+                .and_then(|text| Meta::parse(&text));
+            let entry = Entry {
+                name: match &meta {
+                    Some(meta) => meta.name.clone(),
+                    None => root.root().join("recovered").join(&alias),
+                },
+                timestamp: meta.as_ref().map_or(now, |meta| meta.removed),
+                alias,
+                root: root.clone(),
+                batch: None,
+            };
+            check.issues.push(Issue {
+                name: meta.map(|_| entry.name.clone()),
+                ..Issue::of(Problem::Orphan, &entry)
+            });
+            check.adopted.push(entry);
+        }
+        check.adopted.sort_by_key(|e| e.timestamp);
+        Ok(check)
+    }
+
+    /// The history once repaired, oldest first.
+    pub fn history(&self) -> Vec<Vec<&Entry>> {
+        let mut blocks = self
+            .kept
+            .iter()
+            .map(|batch| batch.iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        blocks.push(self.adopted.iter().collect());
+        blocks
+    }
+
+    /// Lines of the repaired history.
+    #[cfg(test)]
+    pub fn records(&self) -> Vec<String> {
+        self.history()
+            .iter()
+            .flatten()
+            .map(|entry| entry.record())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use std::path::Path;
+
+    #[test]
+    fn problems() {
+        let root = Root::new(PathBuf::from("/trash"));
+        let mut storage = MemoryStorage::new();
+        for name in &["/a", "/b", "/c"] {
+            storage.insert(*name, b"data");
+            storage
+                .store(&root, &name[1..], Path::new(name), 0)
+                .unwrap();
+        }
+        storage.set_history(
+            &root,
+            b"#rem-history 2\n\na|/a|100\ngone|/gone|100\n\nbroken\na|/a|200\n",
+        );
+        let check = Check::run(&storage, &root, 500).unwrap();
+        let problems = check
+            .issues
+            .iter()
+            .map(|i| (i.problem, i.alias.as_deref().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                (Problem::Corrupted, ""),
+                (Problem::Dangling, "gone"),
+                (Problem::Duplicate, "a"),
+                (Problem::Orphan, "b"),
+                (Problem::Orphan, "c"),
+            ]
+        );
+        assert_eq!(check.issues[0].line, Some(6));
+        assert_eq!(check.kept.len(), 1);
+        // Nothing was recorded about them, not even where they came from
+        assert_eq!(check.adopted[0].name, Path::new("/trash/recovered/b"));
+        assert_eq!(check.adopted[0].timestamp, 500);
+        assert_eq!(check.issues[3].name, None);
+        assert_eq!(
+            check.records(),
+            vec![
+                "a|/a|100",
+                "b|/trash/recovered/b|500",
+                "c|/trash/recovered/c|500"
+            ]
+        );
+        assert!(check.discarded.is_empty());
+    }
+}
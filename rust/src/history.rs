@@ -0,0 +1,353 @@
+//! On-disk format of the `history` file.
+//!
+//! ```text
+//! #rem-history 2
+//!
+//! alias|name|timestamp|batch
+//! alias|name|timestamp|batch
+//!
+//! alias|name|timestamp|batch
+//! ```
+//!
+//! Batches are separated by blank lines, oldest first. The last field
+//! identifies the invocation that recorded the batch, see `Entry::batch`.
+//! It is missing from entries recorded by older versions, which ignore it
+//! when reading. Names are escaped so that they never contain a `|`, a
+//! newline, or invalid UTF-8: backslashes are doubled and any such byte is
+//! written as `\xHH`.
+//!
+//! Files written before the header was introduced (version 1) use the same
+//! layout with raw names and are still readable. They are rewritten in the
+//! current format by `migrate`.
+
+use crate::command::Error;
+use crate::config::Root;
+use crate::select::Entry;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+pub const VERSION: u32 = 2;
+const MAGIC: &str = "#rem-history";
+
+fn header() -> String {
+    format!("{} {}\n", MAGIC, VERSION)
+}
+
+/// Encode arbitrary bytes as a string without separators.
+pub fn escape(name: &[u8]) -> String {
+    let mut res = String::with_capacity(name.len());
+    for chunk in name.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => res.push_str("\\\\"),
+                '|' | '\u{7f}' => res.push_str(&format!("\\x{:02x}", c as u32)),
+                c if (c as u32) < 0x20 => res.push_str(&format!("\\x{:02x}", c as u32)),
+                c => res.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            res.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    res
+}
+
+/// Inverse of `escape`, fails on malformed escape sequences.
+pub fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            res.push(b);
+            continue;
+        }
+        match bytes.next()? {
+            b'\\' => res.push(b'\\'),
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                res.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+    Some(res)
+}
+
+pub fn record(entry: &Entry) -> String {
+    let mut line = format!(
+        "{}|{}|{}",
+        entry.alias,
+        escape(entry.name.as_os_str().as_bytes()),
+        entry.timestamp
+    );
+    if let Some(batch) = entry.batch {
+        line.push_str(&format!("|{}", batch));
+    }
+    line
+}
+
+/// Split off the header, returning the version and the rest of the file.
+fn version(contents: &[u8]) -> Result<(u32, &[u8]), Error> {
+    if !contents.starts_with(MAGIC.as_bytes()) {
+        return Ok((1, contents));
+    }
+    let end = contents
+        .iter()
+        .position(|&b| b == b'\n')
+        .unwrap_or(contents.len());
+    let (first, rest) = contents.split_at(end);
+    let first = String::from_utf8_lossy(first);
+    match first[MAGIC.len()..].trim().parse::<u32>() {
+        Ok(v) if v <= VERSION => Ok((v, rest)),
+        _ => Err(Error::UnsupportedHistory(first.to_string(), VERSION)),
+    }
+}
+
+/// Read the batches of a history file, oldest first, each in the order in
+/// which its entries were removed.
+pub fn parse(contents: &[u8], root: &Root) -> Result<Vec<Vec<Entry>>, Error> {
+    let (blocks, corrupted) = parse_lenient(contents, root)?;
+    match corrupted.into_iter().next() {
+        Some((_, err)) => Err(err),
+        None => Ok(blocks),
+    }
+}
+
+/// Batches that could be read, and the lines that could not with their
+/// number.
+pub type Lenient = (Vec<Vec<Entry>>, Vec<(usize, Error)>);
+
+/// Same as `parse`, but lines that cannot be read are skipped and returned
+/// with their line number instead. Only an unsupported version is fatal.
+pub fn parse_lenient(contents: &[u8], root: &Root) -> Result<Lenient, Error> {
+    let (version, body) = version(contents)?;
+    let mut idx = 1;
+    let mut blocks = Vec::new();
+    let mut batch = Vec::new();
+    let mut corrupted = Vec::new();
+    // The header, if any, is left at the start of the first line
+    for (num, line) in body.split(|&b| b == b'\n').enumerate() {
+        if line.is_empty() {
+            if !batch.is_empty() {
+                blocks.push(std::mem::take(&mut batch));
+            }
+            continue;
+        }
+        let entry = if version == 1 {
+            parse_legacy(line, idx, root)
+        } else {
+            std::str::from_utf8(line)
+                .map_err(|_| Error::CorruptedName(String::from_utf8_lossy(line).to_string()))
+                .and_then(|line| parse_line(line, idx, root))
+        };
+        match entry {
+            Ok(entry) => batch.push(entry),
+            Err(err) => corrupted.push((num + 1, err)),
+        }
+        idx += 1;
+    }
+    if !batch.is_empty() {
+        blocks.push(batch);
+    }
+    Ok((blocks, corrupted))
+}
+
+fn parse_timestamp(text: &str) -> Result<u64, Error> {
+    text.parse::<u64>()
+        .map_err(|_| Error::CorruptedTimestamp(text.to_string()))
+}
+
+fn parse_line(line: &str, idx: usize, root: &Root) -> Result<Entry, Error> {
+    let mut data = line.split('|');
+    let alias = data
+        .next()
+        .ok_or_else(|| Error::MissingData(line.to_string(), idx, "alias"))?;
+    let name = data
+        .next()
+        .ok_or_else(|| Error::MissingData(line.to_string(), idx, "name"))?;
+    let timestamp = data
+        .next()
+        .ok_or_else(|| Error::MissingData(line.to_string(), idx, "timestamp"))?;
+    let batch = data.next().map(parse_timestamp).transpose()?;
+    let name = unescape(name).ok_or_else(|| Error::CorruptedName(name.to_string()))?;
+    Ok(Entry {
+        name: PathBuf::from(OsString::from_vec(name)),
+        alias: alias.to_string(),
+        timestamp: parse_timestamp(timestamp)?,
+        root: root.clone(),
+        batch,
+    })
+}
+
+/// Names were written as-is by version 1, so a `|` in a name splits it
+/// into several fields. Since neither the alias nor the timestamp can
+/// contain one, everything in between belongs to the name.
+fn parse_legacy(line: &[u8], idx: usize, root: &Root) -> Result<Entry, Error> {
+    let text = || String::from_utf8_lossy(line).to_string();
+    let first = line
+        .iter()
+        .position(|&b| b == b'|')
+        .ok_or_else(|| Error::MissingData(text(), idx, "name"))?;
+    let last = line
+        .iter()
+        .rposition(|&b| b == b'|')
+        .filter(|&last| last > first)
+        .ok_or_else(|| Error::MissingData(text(), idx, "timestamp"))?;
+    let timestamp = String::from_utf8_lossy(&line[last + 1..]);
+    Ok(Entry {
+        name: PathBuf::from(OsString::from_vec(line[first + 1..last].to_vec())),
+        alias: String::from_utf8_lossy(&line[..first]).to_string(),
+        timestamp: parse_timestamp(&timestamp)?,
+        root: root.clone(),
+        batch: None,
+    })
+}
+
+fn failed_to_write(file: &Path) -> Error {
+    Error::FailedToWrite(file.to_string_lossy().to_string(), None)
+}
+
+/// Full contents of a history holding `blocks`, oldest first.
+pub fn format(blocks: &[Vec<&Entry>]) -> String {
+    let mut contents = header();
+    for batch in blocks.iter().filter(|b| !b.is_empty()) {
+        contents.push_str(&format_batch(batch, false));
+    }
+    contents
+}
+
+/// Text to add at the end of a history to record `batch`,
+/// including the header if the history is still `empty`.
+pub fn format_batch(batch: &[&Entry], empty: bool) -> String {
+    let mut contents = String::new();
+    if batch.is_empty() {
+        return contents;
+    }
+    if empty {
+        contents.push_str(&header());
+    }
+    contents.push('\n');
+    for entry in batch {
+        contents.push_str(&record(entry));
+        contents.push('\n');
+    }
+    contents
+}
+
+/// Replace the contents of the history of `root` with `blocks`, oldest first.
+pub fn write(root: &Root, blocks: &[Vec<&Entry>]) -> Result<(), Error> {
+    let history = root.history();
+    let contents = format(blocks);
+    let mut tmp = history.to_path_buf();
+    tmp.set_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .and_then(|_| std::fs::rename(&tmp, history))
+        .map_err(|_| failed_to_write(history))
+}
+
+/// Add a new batch at the end of the history of `root`.
+pub fn append(root: &Root, batch: &[&Entry]) -> Result<(), Error> {
+    use std::io::Write;
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let history = root.history();
+    let empty = std::fs::metadata(history).map(|m| m.len()).unwrap_or(0) == 0;
+    let contents = format_batch(batch, empty);
+    std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(history)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .map_err(|_| Error::FailedToWrite(history.to_string_lossy().to_string(), Some(contents)))
+}
+
+/// Rewrite a history that predates the current format.
+/// Returns whether anything was done.
+pub fn migrate(root: &Root) -> Result<bool, Error> {
+    let history = root.history();
+    let contents = std::fs::read(history)
+        .map_err(|_| Error::HistoryNotReadable(history.to_string_lossy().to_string()))?;
+    let (version, _) = version(&contents)?;
+    if version == VERSION || contents.iter().all(u8::is_ascii_whitespace) {
+        return Ok(false);
+    }
+    let blocks = parse(&contents, root)?;
+    let mut backup = history.to_path_buf();
+    backup.set_extension(format!("v{}", version));
+    std::fs::copy(history, &backup).map_err(|_| failed_to_write(&backup))?;
+    let blocks = blocks
+        .iter()
+        .map(|b| b.iter().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    write(root, &blocks)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn root() -> Root {
+        Root::new(std::path::PathBuf::from("/trash"))
+    }
+
+    #[test]
+    fn escape_roundtrip() {
+        let names: &[&[u8]] = &[
+            b"/home/user/foo.txt",
+            b"/tmp/a|b",
+            b"/tmp/multi\nline",
+            b"/tmp/back\\slash\\x7c",
+            b"/tmp/latin1-\xe9t\xe9",
+            "/tmp/unicode-\u{e9}t\u{e9}".as_bytes(),
+        ];
+        for name in names {
+            let escaped = escape(name);
+            assert!(!escaped.contains('|') && !escaped.contains('\n'));
+            assert_eq!(unescape(&escaped).as_deref(), Some(*name));
+        }
+        assert_eq!(escape(b"/tmp/a|b"), "/tmp/a\\x7cb");
+        assert_eq!(unescape("trailing\\"), None);
+        assert_eq!(unescape("\\q"), None);
+    }
+
+    #[test]
+    fn versions() {
+        let legacy = b"\nabc|/tmp/a|b|100\ndef|/tmp/c\xe9|100\n";
+        let blocks = parse(legacy, &root()).unwrap();
+        assert_eq!(blocks[0][0].name, Path::new("/tmp/a|b"));
+        assert_eq!(blocks[0][1].name.as_os_str().as_bytes(), b"/tmp/c\xe9");
+        let current = b"#rem-history 2\n\nabc|/tmp/a\\x7cb|100\n\ndef|/tmp/c\\xe9|200\n";
+        let blocks = parse(current, &root()).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0][0].name, Path::new("/tmp/a|b"));
+        assert_eq!(blocks[1][0].name.as_os_str().as_bytes(), b"/tmp/c\xe9");
+        assert_eq!(blocks[1][0].timestamp, 200);
+        assert_eq!(blocks[1][0].batch, None);
+        let identified = b"#rem-history 2\n\nabc|/tmp/a|100|1700000000000000000\n";
+        let entry = &parse(identified, &root()).unwrap()[0][0];
+        assert_eq!(entry.batch, Some(1_700_000_000_000_000_000));
+        assert_eq!(record(entry), "abc|/tmp/a|100|1700000000000000000");
+        let damaged =
+            b"#rem-history 2\n\nabc|/tmp/a|100\nbroken\n\n\ndef|/tmp/b|x\nghi|/tmp/c|300\n";
+        let (blocks, corrupted) = parse_lenient(damaged, &root()).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1][0].alias, "ghi");
+        let lines = corrupted.iter().map(|(line, _)| *line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![4, 7]);
+        assert!(matches!(corrupted[0].1, Error::MissingData(_, 2, "name")));
+        assert!(matches!(corrupted[1].1, Error::CorruptedTimestamp(_)));
+        assert!(matches!(
+            parse(damaged, &root()),
+            Err(Error::MissingData(_, 2, _))
+        ));
+        let future = b"#rem-history 3\n\nabc|/tmp/a|100\n";
+        assert!(matches!(
+            parse(future, &root()),
+            Err(Error::UnsupportedHistory(_, _))
+        ));
+    }
+}
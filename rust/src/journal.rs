@@ -0,0 +1,140 @@
+//! Intents recorded in `journal` before files are moved, so that an
+//! operation that was interrupted can be completed or rolled back by the
+//! next invocation.
+//!
+//! ```text
+//! #rem-journal 1
+//! remove|/home/user/.trash|<alias>|/home/user/foo|1626000000
+//! restore|/home/user/.trash|<alias>|/home/user/foo|1626000000|/home/user/foo.0
+//! ```
+//!
+//! Fields are escaped as in the history. The journal is deleted once the
+//! operation is complete, histories included.
+
+use crate::config::Root;
+use crate::history::{escape, unescape};
+use crate::select::Entry;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+pub const HEADER: &str = "#rem-journal 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Remove,
+    /// Where the file is being restored to
+    Restore(PathBuf),
+    Purge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Intent {
+    pub op: Op,
+    pub entry: Entry,
+}
+
+fn path(p: &Path) -> String {
+    escape(p.as_os_str().as_bytes())
+}
+
+impl Intent {
+    pub fn new(op: Op, entry: &Entry) -> Self {
+        Self {
+            op,
+            entry: entry.clone(),
+        }
+    }
+
+    pub fn serialize(&self) -> String {
+        let op = match self.op {
+            Op::Remove => "remove",
+            Op::Restore(_) => "restore",
+            Op::Purge => "purge",
+        };
+        let mut line = format!(
+            "{}|{}|{}|{}|{}",
+            op,
+            path(self.entry.root.root()),
+            self.entry.alias,
+            path(&self.entry.name),
+            self.entry.timestamp
+        );
+        if let Op::Restore(dest) = &self.op {
+            line.push('|');
+            line.push_str(&path(dest));
+        }
+        line.push('\n');
+        line
+    }
+
+    /// Roots are read as roots of `rem`, see `Config::roots` for the actual
+    /// ones.
+    pub fn parse(line: &str) -> Option<Self> {
+        let path = |s: &str| unescape(s).map(|b| PathBuf::from(OsString::from_vec(b)));
+        let mut fields = line.split('|');
+        let op = fields.next()?;
+        let entry = Entry {
+            root: Root::new(path(fields.next()?)?),
+            alias: fields.next()?.to_string(),
+            name: path(fields.next()?)?,
+            timestamp: fields.next()?.parse().ok()?,
+            batch: None,
+        };
+        let op = match op {
+            "remove" => Op::Remove,
+            "restore" => Op::Restore(path(fields.next()?)?),
+            "purge" => Op::Purge,
+            _ => return None,
+        };
+        Some(Self { op, entry })
+    }
+}
+
+/// Intents of a journal. A last line without a newline was not completely
+/// written and is ignored.
+pub fn parse(contents: &[u8]) -> Vec<Intent> {
+    let mut lines = contents.split(|&b| b == b'\n').collect::<Vec<_>>();
+    lines.pop();
+    lines
+        .into_iter()
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .filter(|line| *line != HEADER)
+        .filter_map(Intent::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let entry = Entry {
+            name: PathBuf::from("/home/a|b c"),
+            alias: String::from("xyz"),
+            timestamp: 100,
+            root: Root::new(PathBuf::from("/trash")),
+            batch: None,
+        };
+        let ops = vec![
+            Op::Remove,
+            Op::Restore(PathBuf::from("/home/a|b c.0")),
+            Op::Purge,
+        ];
+        let mut journal = format!("{}\n", HEADER);
+        for op in ops {
+            let intent = Intent::new(op, &entry);
+            assert_eq!(
+                Intent::parse(intent.serialize().trim_end()),
+                Some(intent.clone())
+            );
+            journal.push_str(&intent.serialize());
+        }
+        // Interrupted while writing
+        journal.push_str("remove|/trash|xyz|/home/a|10");
+        let intents = parse(journal.as_bytes());
+        assert_eq!(intents.len(), 3);
+        assert_eq!(intents[1].op, Op::Restore(PathBuf::from("/home/a|b c.0")));
+    }
+}
@@ -0,0 +1,130 @@
+//! Trashes left by the shell implementation, `shell/rem.sh`.
+//!
+//! They live in `$HOME/.trash` by default and have the layout of a root,
+//! with a version 1 history, but aliases are base64 and `meta` is the plain
+//! text shown by `--info`:
+//!
+//! ```text
+//! notes.txt
+//! 2021-07-11 12:30:00
+//!
+//! <output of ls -Flah>
+//!
+//! <output of file>
+//! ```
+
+use crate::command::Error;
+use crate::config::Root;
+use crate::select::Entry;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// `$REM_LEGACY_ROOT`, falling back to `$HOME/.trash`.
+pub fn home_root() -> Option<PathBuf> {
+    match std::env::var_os("REM_LEGACY_ROOT").filter(|root| !root.is_empty()) {
+        Some(root) => Some(PathBuf::from(root)),
+        None => std::env::var_os("HOME").map(|home| Path::new(&home).join(".trash")),
+    }
+}
+
+/// Whether `dir` looks like a trash of the shell implementation.
+pub fn detect(dir: &Path) -> bool {
+    let root = Root::new(dir.to_path_buf());
+    root.history().is_file() && root.registry().is_dir()
+}
+
+/// Whether `alias` can be kept as is, i.e. it is made of base64 or of the
+/// characters of our own aliases.
+pub fn valid_alias(alias: &str) -> bool {
+    !alias.is_empty()
+        && alias
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'=')
+}
+
+/// History holding `blocks`, oldest first, as the shell implementation
+/// writes it: raw names, no header, and a blank line before each batch.
+pub fn format_history(blocks: &[Vec<&Entry>]) -> Vec<u8> {
+    let mut contents = Vec::new();
+    for batch in blocks.iter().filter(|b| !b.is_empty()) {
+        contents.push(b'\n');
+        for entry in batch {
+            contents.extend_from_slice(entry.alias.as_bytes());
+            contents.push(b'|');
+            contents.extend_from_slice(entry.name.as_os_str().as_bytes());
+            contents.extend_from_slice(format!("|{}\n", entry.timestamp).as_bytes());
+        }
+    }
+    contents
+}
+
+/// Replace the history of `root` so that the shell implementation can
+/// still read it.
+pub fn write_history(root: &Root, blocks: &[Vec<&Entry>]) -> Result<(), Error> {
+    let history = root.history();
+    let mut tmp = history.to_path_buf();
+    tmp.set_extension("tmp");
+    std::fs::write(&tmp, format_history(blocks))
+        .and_then(|_| std::fs::rename(&tmp, history))
+        .map_err(|_| Error::FailedToWrite(history.to_string_lossy().to_string(), None))
+}
+
+/// What can be read from a `meta` file written by the shell implementation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    pub basename: OsString,
+    /// Unknown if the date cannot be read
+    pub removed: Option<u64>,
+}
+
+impl Meta {
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        let mut lines = contents.split(|&b| b == b'\n');
+        let basename = lines.next().filter(|line| !line.is_empty())?;
+        let removed = lines
+            .next()
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .and_then(|date| crate::xdg::parse_date(&date.trim().replacen(' ', "T", 1)));
+        Some(Self {
+            basename: OsString::from_vec(basename.to_vec()),
+            removed,
+        })
+    }
+
+    pub fn read(root: &Root, alias: &str) -> Option<Self> {
+        Self::parse(&std::fs::read(root.registry().join(alias).join("meta")).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn old_history() {
+        let root = Root::new(PathBuf::from("/home/user/.trash"));
+        let text = b"\nMTIz|/home/a|b|100\nNDU2|/home/\xff|100\n\nT3Jw|/home/c|200\n";
+        let blocks = crate::history::parse(text, &root).unwrap();
+        let blocks = blocks
+            .iter()
+            .map(|b| b.iter().collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(format_history(&blocks), text.to_vec());
+        assert!(format_history(&[vec![]]).is_empty());
+    }
+
+    #[test]
+    fn old_meta() {
+        let text = b"notes.txt\n2021-07-11 12:30:00\n\n-rw-r--r-- 1 user user 12 notes.txt\n";
+        let meta = Meta::parse(text).unwrap();
+        assert_eq!(meta.basename, OsString::from("notes.txt"));
+        assert_eq!(meta.removed, crate::xdg::parse_date("2021-07-11T12:30:00"));
+        let undated = Meta::parse(b"caf\xe9\n").unwrap();
+        assert_eq!(undated.basename, OsString::from_vec(b"caf\xe9".to_vec()));
+        assert_eq!(undated.removed, None);
+        assert_eq!(Meta::parse(b""), None);
+        assert!(valid_alias("MTIzNDU2Nzg5Cg=="));
+        assert!(!valid_alias("a|b") && !valid_alias(""));
+    }
+}
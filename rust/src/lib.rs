@@ -0,0 +1,46 @@
+//! Move files to a trash from which they can be listed, restored, or
+//! permanently deleted.
+//!
+//! This is the library behind the `rem` command. Most programs only need
+//! a [`Trash`] built from a [`Config`].
+
+pub(crate) mod command;
+pub mod config;
+pub(crate) mod fsck;
+pub(crate) mod history;
+pub(crate) mod journal;
+pub(crate) mod legacy;
+pub(crate) mod lock;
+pub(crate) mod meta;
+pub(crate) mod report;
+pub mod select;
+pub(crate) mod sniff;
+pub(crate) mod stats;
+pub(crate) mod storage;
+#[cfg(test)]
+mod testing;
+pub(crate) mod transfer;
+pub mod trash;
+pub(crate) mod xdg;
+
+pub use command::Error;
+pub use config::{Config, Root};
+pub use fsck::{Issue, Problem};
+pub use journal::Op;
+pub use lock::Lock;
+pub use meta::{Child, Kind, Meta};
+pub use select::{Entries, Entry, Select, Selection};
+pub use stats::{Largest, Parent, Stats};
+pub use storage::{FsStorage, MemoryStorage, Storage};
+pub use trash::{Removal, RestoreOptions, Results, Trash};
+pub use xdg::XdgStorage;
+
+/// What the `rem` binary needs besides the library: parsing its arguments
+/// and printing its output. None of it is meant to be relied upon.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::command::{Action, Command, Editor};
+    pub use crate::legacy::{detect as is_legacy_trash, home_root as legacy_root};
+    pub use crate::report::{Outcome, Record, Report};
+    pub use crate::xdg::home_trash as xdg_trash;
+}
@@ -0,0 +1,87 @@
+use crate::command::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Exclusive access to the trash, held for as long as the value is alive.
+///
+/// Ownership is an advisory lock (`flock`) on the lock file, which the
+/// system releases when its owner exits, even if it was killed before it
+/// could clean up. The file contains the PID of its owner, for messages.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+    /// Holds the lock until closed
+    _file: std::fs::File,
+}
+
+impl Lock {
+    pub fn acquire(path: &Path) -> Result<Self, Error> {
+        let failed = || Error::FailedToWrite(path.to_string_lossy().to_string(), None);
+        loop {
+            let mut file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(|_| failed())?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                if std::io::Error::last_os_error().kind() != ErrorKind::WouldBlock {
+                    return Err(failed());
+                }
+                let mut pid = String::new();
+                let owner = file
+                    .read_to_string(&mut pid)
+                    .ok()
+                    .and_then(|_| pid.trim().parse::<i32>().ok());
+                return Err(Error::Locked(path.to_string_lossy().to_string(), owner));
+            }
+            // The previous owner deletes the file right before releasing it,
+            // in which case the lock that was obtained guards nothing.
+            let current = path.symlink_metadata().ok();
+            let opened = file.metadata().map_err(|_| failed())?;
+            if current.map(|m| (m.dev(), m.ino())) != Some((opened.dev(), opened.ino())) {
+                continue;
+            }
+            let pid = std::process::id();
+            file.set_len(0)
+                .and_then(|_| writeln!(file, "{}", pid))
+                .map_err(|_| {
+                    Error::FailedToWrite(path.to_string_lossy().to_string(), Some(pid.to_string()))
+                })?;
+            return Ok(Self {
+                path: path.to_path_buf(),
+                _file: file,
+            });
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Deleted while still held, released when the file is closed
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn exclusive() {
+        let dir = TempDir::new("lock");
+        let path = dir.join("lock");
+        let lock = Lock::acquire(&path).unwrap();
+        let pid = std::process::id() as i32;
+        assert!(matches!(Lock::acquire(&path), Err(Error::Locked(_, Some(owner))) if owner == pid));
+        drop(lock);
+        assert!(!path.exists());
+        // Left by a process that did not clean up
+        std::fs::write(&path, "999999999\n").unwrap();
+        drop(Lock::acquire(&path).unwrap());
+    }
+}
@@ -1,16 +1,14 @@
-mod command;
-mod config;
 mod exec;
-mod select;
+
+use rem::cli::Command;
 
 fn main() {
-    let cmd = match command::Command::argparse() {
+    let cmd = match Command::argparse() {
         Ok(cmd) => cmd,
         Err(err) => {
             eprintln!("{}", err);
             std::process::exit(1);
         }
     };
-    let cfg = config::Config::getenv();
-    exec::exec(cmd, cfg);
+    exec::exec(cmd);
 }
@@ -0,0 +1,521 @@
+//! Data recorded about a file right before it is removed, stored as
+//! `registry/<alias>/meta`.
+//!
+//! ```text
+//! #rem-meta 1
+//! name /home/user/foo
+//! removed 1626000000
+//! type dir
+//! mode 755
+//! ...
+//! child file 644 1024 1625000000 bar.txt
+//! ```
+//!
+//! One `key value` pair per line, with names escaped as in the history.
+//! Unknown keys are ignored. Files that do not start with the header were
+//! written by older versions as preformatted text and are shown as-is.
+
+use crate::history::{escape, unescape};
+use std::ffi::{CStr, OsString};
+use std::fmt;
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "#rem-meta 1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    Block,
+    Char,
+}
+
+impl Kind {
+    fn of(meta: &Metadata) -> Self {
+        let ft = meta.file_type();
+        if ft.is_symlink() {
+            Kind::Symlink
+        } else if ft.is_dir() {
+            Kind::Dir
+        } else if ft.is_fifo() {
+            Kind::Fifo
+        } else if ft.is_socket() {
+            Kind::Socket
+        } else if ft.is_block_device() {
+            Kind::Block
+        } else if ft.is_char_device() {
+            Kind::Char
+        } else {
+            Kind::File
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "dir",
+            Kind::Symlink => "symlink",
+            Kind::Fifo => "fifo",
+            Kind::Socket => "socket",
+            Kind::Block => "block",
+            Kind::Char => "char",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "file" => Kind::File,
+            "dir" => Kind::Dir,
+            "symlink" => Kind::Symlink,
+            "fifo" => Kind::Fifo,
+            "socket" => Kind::Socket,
+            "block" => Kind::Block,
+            "char" => Kind::Char,
+            _ => return None,
+        })
+    }
+
+    /// First character of `ls -l`
+    fn letter(self) -> char {
+        match self {
+            Kind::File => '-',
+            Kind::Dir => 'd',
+            Kind::Symlink => 'l',
+            Kind::Fifo => 'p',
+            Kind::Socket => 's',
+            Kind::Block => 'b',
+            Kind::Char => 'c',
+        }
+    }
+
+    /// Suffix of `ls -F`
+    fn suffix(self) -> &'static str {
+        match self {
+            Kind::Dir => "/",
+            Kind::Symlink => "@",
+            Kind::Fifo => "|",
+            Kind::Socket => "=",
+            _ => "",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Child {
+    pub kind: Kind,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: i64,
+    pub name: OsString,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Meta {
+    pub name: PathBuf,
+    pub removed: u64,
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub size: u64,
+    /// Size of the file and of everything below it, unknown for data
+    /// recorded by older versions
+    pub total: Option<u64>,
+    pub nlink: u64,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub target: Option<PathBuf>,
+    pub entries: Option<u64>,
+    /// Description of the contents of regular files
+    pub content: Option<String>,
+    pub children: Vec<Child>,
+}
+
+impl Meta {
+    /// Inspect `file` without following it if it is a symlink.
+    pub fn collect(file: &Path, removed: u64) -> io::Result<Self> {
+        let meta = file.symlink_metadata()?;
+        let kind = Kind::of(&meta);
+        let target = match kind {
+            Kind::Symlink => Some(std::fs::read_link(file)?),
+            _ => None,
+        };
+        let mut children = Vec::new();
+        if kind == Kind::Dir {
+            for child in std::fs::read_dir(file)? {
+                let child = child?;
+                let meta = match child.path().symlink_metadata() {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                children.push(Child {
+                    kind: Kind::of(&meta),
+                    mode: meta.mode() & 0o7777,
+                    size: meta.len(),
+                    mtime: meta.mtime(),
+                    name: child.file_name(),
+                });
+            }
+            children.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        Ok(Self {
+            name: file.to_path_buf(),
+            removed,
+            kind,
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            user: user_name(meta.uid()),
+            group: group_name(meta.gid()),
+            size: meta.len(),
+            total: Some(total_size(file)),
+            nlink: meta.nlink(),
+            atime: meta.atime(),
+            mtime: meta.mtime(),
+            ctime: meta.ctime(),
+            target,
+            entries: if kind == Kind::Dir {
+                Some(children.len() as u64)
+            } else {
+                None
+            },
+            // Unreadable contents should not prevent the removal
+            content: match kind {
+                Kind::File => crate::sniff::sniff(file).ok(),
+                _ => None,
+            },
+            children,
+        })
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut res = format!("{}\n", HEADER);
+        let mut field = |key: &str, value: String| {
+            res.push_str(key);
+            res.push(' ');
+            res.push_str(&value);
+            res.push('\n');
+        };
+        field("name", escape(self.name.as_os_str().as_bytes()));
+        field("removed", self.removed.to_string());
+        field("type", self.kind.as_str().to_string());
+        field("mode", format!("{:o}", self.mode));
+        field("uid", self.uid.to_string());
+        field("gid", self.gid.to_string());
+        if let Some(user) = &self.user {
+            field("user", escape(user.as_bytes()));
+        }
+        if let Some(group) = &self.group {
+            field("group", escape(group.as_bytes()));
+        }
+        field("size", self.size.to_string());
+        if let Some(total) = self.total {
+            field("total", total.to_string());
+        }
+        field("nlink", self.nlink.to_string());
+        field("atime", self.atime.to_string());
+        field("mtime", self.mtime.to_string());
+        field("ctime", self.ctime.to_string());
+        if let Some(target) = &self.target {
+            field("target", escape(target.as_os_str().as_bytes()));
+        }
+        if let Some(entries) = self.entries {
+            field("entries", entries.to_string());
+        }
+        if let Some(content) = &self.content {
+            field("content", escape(content.as_bytes()));
+        }
+        for child in &self.children {
+            field(
+                "child",
+                format!(
+                    "{} {:o} {} {} {}",
+                    child.kind.as_str(),
+                    child.mode,
+                    child.size,
+                    child.mtime,
+                    escape(child.name.as_bytes())
+                ),
+            );
+        }
+        res
+    }
+
+    /// `None` if `text` is not in the structured format.
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+        if lines.next()? != HEADER {
+            return None;
+        }
+        let path = |s: &str| unescape(s).map(|b| PathBuf::from(OsString::from_vec(b)));
+        let string = |s: &str| unescape(s).map(|b| String::from_utf8_lossy(&b).to_string());
+        let mut meta = Self {
+            name: PathBuf::new(),
+            removed: 0,
+            kind: Kind::File,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            user: None,
+            group: None,
+            size: 0,
+            total: None,
+            nlink: 1,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            target: None,
+            entries: None,
+            content: None,
+            children: Vec::new(),
+        };
+        for line in lines {
+            let (key, value) = match line.split_once(' ') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            match key {
+                "name" => meta.name = path(value)?,
+                "removed" => meta.removed = value.parse().ok()?,
+                "type" => meta.kind = Kind::parse(value)?,
+                "mode" => meta.mode = u32::from_str_radix(value, 8).ok()?,
+                "uid" => meta.uid = value.parse().ok()?,
+                "gid" => meta.gid = value.parse().ok()?,
+                "user" => meta.user = string(value),
+                "group" => meta.group = string(value),
+                "size" => meta.size = value.parse().ok()?,
+                "total" => meta.total = value.parse().ok(),
+                "nlink" => meta.nlink = value.parse().ok()?,
+                "atime" => meta.atime = value.parse().ok()?,
+                "mtime" => meta.mtime = value.parse().ok()?,
+                "ctime" => meta.ctime = value.parse().ok()?,
+                "target" => meta.target = path(value),
+                "entries" => meta.entries = value.parse().ok(),
+                "content" => meta.content = string(value),
+                "child" => {
+                    let mut data = value.splitn(5, ' ');
+                    meta.children.push(Child {
+                        kind: Kind::parse(data.next()?)?,
+                        mode: u32::from_str_radix(data.next()?, 8).ok()?,
+                        size: data.next()?.parse().ok()?,
+                        mtime: data.next()?.parse().ok()?,
+                        name: OsString::from_vec(unescape(data.next()?)?),
+                    });
+                }
+                _ => (),
+            }
+        }
+        Some(meta)
+    }
+
+    pub fn write(&self, file: &Path) -> io::Result<()> {
+        std::fs::write(file, self.serialize())
+    }
+}
+
+/// Mimics `ls -l` for the removed file, followed by the contents of
+/// directories.
+impl fmt::Display for Meta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let basename = self
+            .name
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.name.to_string_lossy().to_string());
+        writeln!(f, "{}", basename)?;
+        writeln!(f, "{}", format_date(self.removed as i64, true))?;
+        writeln!(f)?;
+        let owner = |name: &Option<String>, id: u32| match name {
+            Some(name) => name.clone(),
+            None => id.to_string(),
+        };
+        write!(
+            f,
+            "{} {} {} {} {:>5} {} {}{}",
+            permissions(self.kind, self.mode),
+            self.nlink,
+            owner(&self.user, self.uid),
+            owner(&self.group, self.gid),
+            human_size(self.size),
+            format_date(self.mtime, false),
+            self.name.to_string_lossy(),
+            self.kind.suffix(),
+        )?;
+        if let Some(target) = &self.target {
+            write!(f, " -> {}", target.to_string_lossy())?;
+        }
+        writeln!(f)?;
+        if let Some(content) = &self.content {
+            writeln!(f, "{}", content)?;
+        }
+        if let Some(entries) = self.entries {
+            match self.total {
+                Some(total) => writeln!(f, "{} entries, {} in total", entries, human_size(total))?,
+                None => writeln!(f, "{} entries", entries)?,
+            }
+            for child in &self.children {
+                writeln!(
+                    f,
+                    "  {} {:>5} {} {}{}",
+                    permissions(child.kind, child.mode),
+                    human_size(child.size),
+                    format_date(child.mtime, false),
+                    child.name.to_string_lossy(),
+                    child.kind.suffix(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apparent size of `path` and of everything below it, without following
+/// symlinks. Whatever cannot be read counts as empty.
+pub fn total_size(path: &Path) -> u64 {
+    let meta = match path.symlink_metadata() {
+        Ok(meta) => meta,
+        Err(_) => return 0,
+    };
+    let mut total = meta.len();
+    if meta.is_dir() {
+        if let Ok(dir) = std::fs::read_dir(path) {
+            total += dir
+                .filter_map(Result::ok)
+                .map(|child| total_size(&child.path()))
+                .sum::<u64>();
+        }
+    }
+    total
+}
+
+fn permissions(kind: Kind, mode: u32) -> String {
+    let mut res = String::with_capacity(10);
+    res.push(kind.letter());
+    let special = [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')];
+    for (i, (bit, letter)) in special.iter().enumerate() {
+        let shift = 6 - 3 * i;
+        let rwx = (mode >> shift) & 0o7;
+        res.push(if rwx & 0o4 != 0 { 'r' } else { '-' });
+        res.push(if rwx & 0o2 != 0 { 'w' } else { '-' });
+        res.push(match (rwx & 0o1 != 0, mode & bit != 0) {
+            (true, true) => *letter,
+            (false, true) => letter.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    res
+}
+
+pub fn human_size(bytes: u64) -> String {
+    let units = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return format!("{}", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if size < 10.0 {
+        format!("{:.1}{}", size, units[unit])
+    } else {
+        format!("{:.0}{}", size, units[unit])
+    }
+}
+
+/// Local time as `YYYY-MM-DD HH:MM[:SS]`
+pub fn format_date(timestamp: i64, seconds: bool) -> String {
+    let time = timestamp as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return timestamp.to_string();
+    }
+    let date = format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    );
+    if seconds {
+        format!("{}:{:02}", date, tm.tm_sec)
+    } else {
+        date
+    }
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut res = std::ptr::null_mut();
+    let ok = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut res) };
+    if ok != 0 || res.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().to_string())
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut res = std::ptr::null_mut();
+    let ok = unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut res) };
+    if ok != 0 || res.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(grp.gr_name) }.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn roundtrip() {
+        let dir = TempDir::new("meta");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("data"), "0123456789").unwrap();
+        std::os::unix::fs::symlink("data", dir.join("link")).unwrap();
+        let meta = Meta::collect(&dir, 100).unwrap();
+        assert_eq!(meta.kind, Kind::Dir);
+        assert_eq!(meta.entries, Some(3));
+        let names = meta.children.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["data", "link", "sub"]);
+        assert_eq!(meta.children[0].size, 10);
+        let dirs = dir.symlink_metadata().unwrap().len() + dir.join("sub").symlink_metadata().unwrap().len();
+        assert_eq!(meta.total, Some(dirs + 10 + 4));
+        assert_eq!(Meta::parse(&meta.serialize()), Some(meta));
+        let data = Meta::collect(&dir.join("data"), 100).unwrap();
+        assert_eq!(data.content.as_deref(), Some("ASCII text"));
+        assert_eq!(Meta::parse(&data.serialize()), Some(data));
+        let link = Meta::collect(&dir.join("link"), 100).unwrap();
+        assert_eq!(link.kind, Kind::Symlink);
+        assert_eq!(link.target, Some(PathBuf::from("data")));
+        assert_eq!(Meta::parse(&link.serialize()), Some(link));
+        assert_eq!(Meta::parse("foo\n2021-07-01\n"), None);
+    }
+
+    #[test]
+    fn ls_format() {
+        assert_eq!(permissions(Kind::File, 0o644), "-rw-r--r--");
+        assert_eq!(permissions(Kind::Dir, 0o1777), "drwxrwxrwt");
+        assert_eq!(permissions(Kind::File, 0o4644), "-rwSr--r--");
+        assert_eq!(human_size(512), "512");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(300 * 1024 * 1024), "300M");
+    }
+}
@@ -0,0 +1,416 @@
+//! Machine-readable output, enabled by `--json` or `--format tsv`.
+//!
+//! Each entry that a command lists or acts upon becomes one record on
+//! stdout: a JSON object per line, or a line of tab-separated fields in the
+//! order
+//!
+//! ```text
+//! index block alias path timestamp size outcome dest error
+//! ```
+//!
+//! followed for `--info` by `type mode user group mtime content`.
+//! `--stats` prints a single object, or `key value...` lines, and `--fsck`
+//! one record per problem with the fields
+//! `problem root alias path line detail repaired`.
+//! Missing values are empty (TSV) or `null` (JSON), and TSV fields are
+//! escaped as in the history file. In JSON, bytes of a path that are not
+//! valid UTF-8 are written as the lone surrogates `\udc80` to `\udcff`,
+//! as Python's `surrogateescape` does. Errors that are not tied to an entry
+//! go to stderr in the same format.
+
+use crate::command::Error;
+use crate::fsck::Issue;
+use crate::history::escape;
+use crate::meta::Meta;
+use crate::select::Entry;
+use crate::stats::Stats;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Human,
+    Json,
+    Tsv,
+}
+
+/// What happened to an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Removed,
+    Restored,
+    Deleted,
+    /// Moved from another trash
+    Imported,
+    /// Copied out of the trash
+    Extracted,
+    /// Purged to make room for newer entries
+    Evicted,
+    /// Would have been acted upon outside of `--sandbox`
+    Planned,
+    Aborted,
+    Failed,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Removed => "removed",
+            Outcome::Restored => "restored",
+            Outcome::Deleted => "deleted",
+            Outcome::Imported => "imported",
+            Outcome::Extracted => "extracted",
+            Outcome::Evicted => "evicted",
+            Outcome::Planned => "planned",
+            Outcome::Aborted => "aborted",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Record {
+    pub index: Option<usize>,
+    pub block: Option<usize>,
+    pub alias: Option<String>,
+    pub path: PathBuf,
+    pub timestamp: Option<u64>,
+    pub size: Option<u64>,
+    pub outcome: Option<Outcome>,
+    /// Where the file ended up, if not at `path`
+    pub dest: Option<PathBuf>,
+    pub error: Option<(String, String, String)>,
+    /// Only included by `--info`
+    pub meta: Option<Meta>,
+}
+
+impl Record {
+    /// `meta` is what was recorded when `entry` was removed, if available.
+    pub fn of(entry: &Entry, meta: Option<&Meta>) -> Self {
+        Self {
+            alias: Some(entry.alias.clone()),
+            path: entry.name.clone(),
+            timestamp: Some(entry.timestamp),
+            size: meta.map(|m| m.total.unwrap_or(m.size)),
+            ..Self::default()
+        }
+    }
+
+    pub fn at(mut self, index: usize, block: usize) -> Self {
+        self.index = Some(index);
+        self.block = Some(block);
+        self
+    }
+
+    pub fn outcome(mut self, outcome: Outcome) -> Self {
+        self.outcome = Some(outcome);
+        self
+    }
+
+    pub fn failed(mut self, err: &Error) -> Self {
+        self.outcome = Some(Outcome::Failed);
+        self.error = err.parts();
+        self
+    }
+
+    pub fn with_meta(mut self, meta: Option<Meta>) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    fn json(&self) -> String {
+        let mut obj = Object::default();
+        obj.field("index", self.index.map(|i| i.to_string()));
+        obj.field("block", self.block.map(|b| b.to_string()));
+        obj.field("alias", self.alias.as_deref().map(json_str));
+        obj.field("path", Some(json_path(&self.path)));
+        obj.field("timestamp", self.timestamp.map(|t| t.to_string()));
+        obj.field("size", self.size.map(|s| s.to_string()));
+        obj.field("outcome", self.outcome.map(|o| json_str(o.as_str())));
+        obj.field("dest", self.dest.as_deref().map(json_path));
+        obj.field("error", self.error.as_ref().map(json_error));
+        if let Some(meta) = &self.meta {
+            let mut m = Object::default();
+            m.field("type", Some(json_str(meta.kind.as_str())));
+            m.field("mode", Some(json_str(&format!("{:o}", meta.mode))));
+            m.field("uid", Some(meta.uid.to_string()));
+            m.field("gid", Some(meta.gid.to_string()));
+            m.field("user", meta.user.as_deref().map(json_str));
+            m.field("group", meta.group.as_deref().map(json_str));
+            m.field("nlink", Some(meta.nlink.to_string()));
+            m.field("atime", Some(meta.atime.to_string()));
+            m.field("mtime", Some(meta.mtime.to_string()));
+            m.field("ctime", Some(meta.ctime.to_string()));
+            m.field("target", meta.target.as_deref().map(json_path));
+            m.field("entries", meta.entries.map(|e| e.to_string()));
+            m.field("content", meta.content.as_deref().map(json_str));
+            obj.field("meta", Some(m.finish()));
+        }
+        obj.finish()
+    }
+
+    fn tsv(&self) -> String {
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        let path = |p: &Path| escape(p.as_os_str().as_bytes());
+        let mut fields = vec![
+            opt(self.index.map(|i| i.to_string())),
+            opt(self.block.map(|b| b.to_string())),
+            opt(self.alias.clone()),
+            path(&self.path),
+            opt(self.timestamp.map(|t| t.to_string())),
+            opt(self.size.map(|s| s.to_string())),
+            opt(self.outcome.map(|o| o.as_str().to_string())),
+            opt(self.dest.as_deref().map(path)),
+            opt(self.error.as_ref().map(|(title, _, _)| escape(title.as_bytes()))),
+        ];
+        if let Some(meta) = &self.meta {
+            let name = |n: &Option<String>, id: u32| match n {
+                Some(n) => escape(n.as_bytes()),
+                None => id.to_string(),
+            };
+            fields.extend(vec![
+                meta.kind.as_str().to_string(),
+                format!("{:o}", meta.mode),
+                name(&meta.user, meta.uid),
+                name(&meta.group, meta.gid),
+                meta.mtime.to_string(),
+                opt(meta.content.as_deref().map(|c| escape(c.as_bytes()))),
+            ]);
+        }
+        fields.join("\t")
+    }
+}
+
+/// Prints records and errors in the format requested on the command line.
+#[derive(Debug, Clone, Copy)]
+pub struct Report {
+    format: Format,
+}
+
+impl Report {
+    pub fn new(format: Format) -> Self {
+        Self { format }
+    }
+
+    /// Whether output is meant to be read by a human, in which case
+    /// commands print their usual messages instead of records.
+    pub fn human(&self) -> bool {
+        self.format == Format::Human
+    }
+
+    pub fn record(&self, rec: &Record) {
+        match self.format {
+            Format::Human => (),
+            Format::Json => println!("{}", rec.json()),
+            Format::Tsv => println!("{}", rec.tsv()),
+        }
+    }
+
+    pub fn stats(&self, stats: &Stats) {
+        match self.format {
+            Format::Human => print!("{}", stats),
+            Format::Json => {
+                let mut obj = Object::default();
+                obj.field("entries", Some(stats.entries.to_string()));
+                obj.field("batches", Some(stats.batches.to_string()));
+                obj.field("size", Some(stats.size.to_string()));
+                obj.field("oldest", stats.oldest.map(|t| t.to_string()));
+                obj.field("newest", stats.newest.map(|t| t.to_string()));
+                let largest = stats.largest.iter().map(|item| {
+                    let mut obj = Object::default();
+                    obj.field("index", Some(item.index.to_string()));
+                    obj.field("alias", Some(json_str(&item.entry.alias)));
+                    obj.field("path", Some(json_path(&item.entry.name)));
+                    obj.field("size", Some(item.size.to_string()));
+                    obj.finish()
+                });
+                obj.field("largest", Some(json_list(largest)));
+                let parents = stats.parents.iter().map(|parent| {
+                    let mut obj = Object::default();
+                    obj.field("path", Some(json_path(&parent.path)));
+                    obj.field("entries", Some(parent.entries.to_string()));
+                    obj.field("size", Some(parent.size.to_string()));
+                    obj.finish()
+                });
+                obj.field("parents", Some(json_list(parents)));
+                println!("{}", obj.finish());
+            }
+            Format::Tsv => {
+                let opt = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or_default();
+                let path = |p: &Path| escape(p.as_os_str().as_bytes());
+                println!("entries\t{}", stats.entries);
+                println!("batches\t{}", stats.batches);
+                println!("size\t{}", stats.size);
+                println!("oldest\t{}", opt(stats.oldest));
+                println!("newest\t{}", opt(stats.newest));
+                for item in &stats.largest {
+                    println!(
+                        "largest\t{}\t{}\t{}\t{}",
+                        item.index,
+                        item.entry.alias,
+                        path(&item.entry.name),
+                        item.size
+                    );
+                }
+                for parent in &stats.parents {
+                    println!(
+                        "parent\t{}\t{}\t{}",
+                        path(&parent.path),
+                        parent.entries,
+                        parent.size
+                    );
+                }
+            }
+        }
+    }
+
+    /// A problem found by `--fsck`, and whether it was fixed.
+    pub fn issue(&self, issue: &Issue, repaired: bool) {
+        match self.format {
+            Format::Human => (),
+            Format::Json => {
+                let mut obj = Object::default();
+                obj.field("problem", Some(json_str(issue.problem.as_str())));
+                obj.field("root", Some(json_path(issue.root.root())));
+                obj.field("alias", issue.alias.as_deref().map(json_str));
+                obj.field("path", issue.name.as_deref().map(json_path));
+                obj.field("line", issue.line.map(|l| l.to_string()));
+                obj.field("detail", issue.detail.as_deref().map(json_str));
+                obj.field("repaired", Some(repaired.to_string()));
+                println!("{}", obj.finish());
+            }
+            Format::Tsv => {
+                let path = |p: &Path| escape(p.as_os_str().as_bytes());
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    issue.problem.as_str(),
+                    path(issue.root.root()),
+                    issue.alias.as_deref().unwrap_or_default(),
+                    issue.name.as_deref().map(path).unwrap_or_default(),
+                    issue.line.map(|l| l.to_string()).unwrap_or_default(),
+                    issue.detail.as_deref().map(|d| escape(d.as_bytes())).unwrap_or_default(),
+                    repaired
+                );
+            }
+        }
+    }
+
+    pub fn error(&self, err: &Error) {
+        match (self.format, err.parts()) {
+            (_, None) => (),
+            (Format::Human, _) => eprintln!("{}", err),
+            (Format::Json, Some(parts)) => {
+                let mut obj = Object::default();
+                obj.field("error", Some(json_error(&parts)));
+                eprintln!("{}", obj.finish());
+            }
+            (Format::Tsv, Some((title, message, hint))) => eprintln!(
+                "error\t{}\t{}\t{}",
+                escape(title.as_bytes()),
+                escape(message.as_bytes()),
+                escape(hint.as_bytes())
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Object {
+    fields: Vec<String>,
+}
+
+impl Object {
+    /// `value` must already be valid JSON, `None` becomes `null`.
+    fn field(&mut self, key: &str, value: Option<String>) {
+        let value = value.unwrap_or_else(|| "null".to_string());
+        self.fields.push(format!("{}:{}", json_str(key), value));
+    }
+
+    fn finish(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+fn json_error((title, message, hint): &(String, String, String)) -> String {
+    let mut obj = Object::default();
+    obj.field("title", Some(json_str(title)));
+    obj.field("message", Some(json_str(message)));
+    obj.field("hint", Some(json_str(hint)));
+    obj.finish()
+}
+
+fn json_list<I: Iterator<Item = String>>(items: I) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+/// Invalid UTF-8 becomes lone surrogates, which no valid text contains,
+/// so that the path can be recovered exactly.
+fn json_path(path: &Path) -> String {
+    let name = path.as_os_str().as_bytes();
+    let mut res = String::with_capacity(name.len() + 2);
+    res.push('"');
+    for chunk in name.utf8_chunks() {
+        push_json_chars(&mut res, chunk.valid());
+        for b in chunk.invalid() {
+            res.push_str(&format!("\\u{:04x}", 0xdc00 + u32::from(*b)));
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn json_str(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    push_json_chars(&mut res, s);
+    res.push('"');
+    res
+}
+
+fn push_json_chars(res: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let rec = Record {
+            index: Some(3),
+            block: Some(2),
+            alias: Some("abc".to_string()),
+            path: PathBuf::from("/tmp/a \"b\"\tc"),
+            timestamp: Some(100),
+            outcome: Some(Outcome::Restored),
+            ..Record::default()
+        };
+        assert_eq!(
+            rec.json(),
+            r#"{"index":3,"block":2,"alias":"abc","path":"/tmp/a \"b\"\tc","timestamp":100,"size":null,"outcome":"restored","dest":null,"error":null}"#
+        );
+        assert_eq!(rec.tsv(), "3\t2\tabc\t/tmp/a \"b\"\\x09c\t100\t\trestored\t\t");
+        let failed = Record {
+            path: PathBuf::from("/tmp/missing"),
+            ..Record::default()
+        }
+        .failed(&Error::FileDoesNotExist("/tmp/missing".to_string()));
+        assert!(failed
+            .json()
+            .contains(r#""outcome":"failed","dest":null,"error":{"title":"File does not exist","#));
+        assert!(failed.tsv().ends_with("\tfailed\t\tFile does not exist"));
+        let raw = PathBuf::from(std::ffi::OsStr::from_bytes(b"/tmp/\xe9t\xc3\xa9"));
+        assert_eq!(json_path(&raw), r#""/tmp/\udce9té""#);
+    }
+}
@@ -1,30 +1,33 @@
 use crate::command::Error;
-use crate::config::Config;
+use crate::config::{Config, Root};
+use crate::storage::Storage;
 use std::collections::BTreeSet;
+use std::path::PathBuf;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct Entry {
-    pub name: String,
+    pub name: PathBuf,
     pub alias: String,
     pub timestamp: u64,
+    pub root: Root,
+    /// Invocation that recorded the entry, shared by the batches that it
+    /// added to several roots. Unknown for entries recorded by older
+    /// versions.
+    pub batch: Option<u64>,
 }
 
-type Selection<'i> = BTreeSet<(usize, &'i Entry)>;
+/// Entries along with their 1-based index.
+pub type Selection<'i> = BTreeSet<(usize, &'i Entry)>;
 
 impl Entry {
     pub fn true_name(&self) -> String {
-        self.name.replace(&std::env::var("HOME").unwrap_or("~".to_string()), "~")
+        self.name
+            .to_string_lossy()
+            .replace(&std::env::var("HOME").unwrap_or("~".to_string()), "~")
     }
 
-    pub fn info(&self, cfg: &Config) {
-        let mut file = cfg.registry().to_path_buf();
-        file.push(&self.alias);
-        file.push("meta");
-        let text = match std::fs::read_to_string(file) {
-            Ok(text) => text,
-            Err(_) => panic!(),
-        };
-        println!("{}", text);
+    pub fn record(&self) -> String {
+        crate::history::record(self)
     }
 }
 
@@ -35,62 +38,98 @@ pub struct Entries {
 }
 
 impl Entries {
-    pub fn from_file(file: &std::path::Path) -> Result<Self, Error> {
-        let contents = match std::fs::read_to_string(file) {
-            Ok(contents) => contents,
-            Err(_) => {
-                return Err(Error::HistoryNotReadable(
-                    file.to_str().unwrap().to_string(),
-                ))
-            }
+    /// Unified view of the histories of all known roots
+    pub fn load<S: Storage + ?Sized>(storage: &S, cfg: &Config) -> Result<Self, Error> {
+        let mut parts = Vec::new();
+        for root in cfg.roots() {
+            parts.push(Self::parse(&storage.read_history(root)?, root)?);
+        }
+        Ok(Self::merge(parts))
+    }
+
+    /// Interleave the batches of several histories from newest to oldest,
+    /// keeping those of each history in their order. A single invocation
+    /// that removed files from several filesystems produced one batch per
+    /// root with the same `Entry::batch`: these are put back together.
+    fn merge(parts: Vec<Self>) -> Self {
+        let mut pending = parts
+            .iter()
+            .map(|part| part.batches().peekable())
+            .collect::<Vec<_>>();
+        // When the batch was recorded, in nanoseconds, as far as is known
+        let recorded = |batch: &[Entry]| match batch[0].batch {
+            Some(id) => id,
+            None => batch.iter().map(|e| e.timestamp).max().unwrap_or(0) * 1_000_000_000,
         };
-        let sep = regex::Regex::new(r"\n{2,}").unwrap();
-        let mut idx = 1;
-        let mut entries = Self::default();
-        for block in sep.split(&contents).collect::<Vec<_>>().into_iter().rev() {
-            if block == "" {
+        let mut merged = Self::default();
+        loop {
+            // Ties go to the first root, i.e. the main one
+            let newest = pending
+                .iter_mut()
+                .enumerate()
+                .filter_map(|(i, batches)| Some((recorded(batches.peek()?), i)))
+                .min_by_key(|&(stamp, i)| (std::cmp::Reverse(stamp), i));
+            let (head, id, root) = match newest {
+                Some((_, i)) => {
+                    let head = pending[i].next().unwrap();
+                    (head, head[0].batch, i)
+                }
+                None => break,
+            };
+            merged.blocks.push(merged.contents.len() + 1);
+            merged.contents.extend(head.iter().cloned());
+            if id.is_none() {
                 continue;
             }
-            entries.blocks.push(idx);
-            for entry in block.split("\n").collect::<Vec<_>>().into_iter().rev() {
-                if entry == "" {
-                    continue;
+            // An operation may also add several batches to the same root
+            for (_, batches) in pending.iter_mut().enumerate().filter(|(i, _)| *i != root) {
+                if let Some(batch) = batches.next_if(|batch| batch[0].batch == id) {
+                    merged.contents.extend(batch.iter().cloned());
                 }
-                let mut data = entry.split("|");
-                let alias = data
-                    .next()
-                    .ok_or_else(|| Error::MissingData(entry.to_string(), idx, "alias"))?
-                    .to_string();
-                let name = data
-                    .next()
-                    .ok_or_else(|| Error::MissingData(entry.to_string(), idx, "name"))?
-                    .to_string();
-                let timestamp_str = data
-                    .next()
-                    .ok_or_else(|| Error::MissingData(entry.to_string(), idx, "timestamp"))?;
-                let timestamp = timestamp_str
-                    .parse::<u64>()
-                    .map_err(|_| Error::CorruptedTimestamp(timestamp_str.to_string()))?;
-                idx += 1;
-                entries.contents.push(Entry { name, alias, timestamp });
             }
         }
-        entries.blocks.push(idx);
-        Ok(entries)
+        merged.blocks.push(merged.contents.len() + 1);
+        merged
     }
 
-    pub fn info<'i>(&self, cfg: &Config, selection: &Selection<'i>) {
-        for (_, e) in selection {
-            e.info(cfg);
+    fn parse(contents: &[u8], root: &Root) -> Result<Self, Error> {
+        let mut entries = Self::default();
+        for batch in crate::history::parse(contents, root)?.into_iter().rev() {
+            entries.blocks.push(entries.contents.len() + 1);
+            entries.contents.extend(batch.into_iter().rev());
         }
+        entries.blocks.push(entries.contents.len() + 1);
+        Ok(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.contents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contents.is_empty()
     }
 
-    pub fn delete<'i>(&self, cfg: &Config, selection: &Selection<'i>) {
-        unimplemented!()
+    /// Entry at 1-based index `idx`, as shown in listings
+    pub fn get(&self, idx: usize) -> Option<&Entry> {
+        self.contents.get(idx.checked_sub(1)?)
     }
 
-    pub fn restore<'i>(&self, cfg: &Config, selection: &Selection<'i>) {
-        unimplemented!()
+    /// All entries with their 1-based index, newest first
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Entry)> {
+        self.contents.iter().enumerate().map(|(i, e)| (i + 1, e))
+    }
+
+    /// Number of the block that contains the entry at index `idx`
+    pub fn block_of(&self, idx: usize) -> usize {
+        self.blocks.partition_point(|&start| start <= idx)
+    }
+
+    /// Entries removed together, newest batch first
+    pub fn batches(&self) -> impl DoubleEndedIterator<Item = &[Entry]> {
+        self.blocks
+            .windows(2)
+            .map(move |bounds| &self.contents[bounds[0] - 1..bounds[1] - 1])
     }
 }
 
@@ -98,7 +137,7 @@ pub trait Select {
     fn select<'i>(&self, entries: &'i Entries, selection: &mut Selection<'i>);
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct Index {
     start: usize,
     end: usize,
@@ -117,7 +156,7 @@ impl Index {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct Time {
     start: u64,
     end: u64,
@@ -127,6 +166,13 @@ impl Time {
     pub fn new(start: u64, end: u64) -> Self {
         Self { start, end }
     }
+
+    /// Whether an entry removed at `timestamp` is between `start` and `end`
+    /// seconds old at time `now`
+    fn matches(&self, now: u64, timestamp: u64) -> bool {
+        let age = now.saturating_sub(timestamp);
+        self.start <= age && age <= self.end
+    }
 }
 
 #[derive(Debug)]
@@ -139,9 +185,17 @@ impl Pattern {
 }
 
 #[derive(Debug)]
-pub struct Fzf {}
+pub struct Fzf {
+    cmd: &'static str,
+}
 
-#[derive(Debug)]
+impl Fzf {
+    pub fn new(cmd: &'static str) -> Self {
+        Self { cmd }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Block {
     start: usize,
     end: usize,
@@ -167,38 +221,111 @@ impl Selector {
     {
         self.0.push(Box::new(sel));
     }
+
+    /// Whether no criteria were given, as opposed to some that match nothing.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 impl Select for Pattern {
     fn select<'i>(&self, entries: &'i Entries, selection: &mut Selection<'i>) {
         for (i, e) in entries.contents.iter().enumerate() {
-            if !selection.contains(&(i, e)) && self.0.is_match(&e.name) {
-                selection.insert((i, e));
+            if self.0.is_match(&e.name.to_string_lossy()) {
+                selection.insert((i + 1, e));
             }
         }
     }
 }
 impl Select for Time {
     fn select<'i>(&self, entries: &'i Entries, selection: &mut Selection<'i>) {
-        unimplemented!()
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for (i, e) in entries.contents.iter().enumerate() {
+            if self.matches(now, e.timestamp) {
+                selection.insert((i + 1, e));
+            }
+        }
     }
 }
 impl Select for Index {
     fn select<'i>(&self, entries: &'i Entries, selection: &mut Selection<'i>) {
-        let max = entries.contents.len() - 1;
-        for i in self.start..=self.end.min(max) {
-            selection.insert((i, &entries.contents[i]));
+        let max = entries.contents.len();
+        for i in self.start.max(1)..=self.end.min(max) {
+            selection.insert((i, &entries.contents[i - 1]));
         }
     }
 }
+/// `text` as a single word for `sh`.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
 impl Select for Fzf {
     fn select<'i>(&self, entries: &'i Entries, selection: &mut Selection<'i>) {
-        unimplemented!()
+        use std::io::Write;
+        use std::process::Stdio;
+        let rem = std::env::current_exe()
+            .ok()
+            .and_then(|p| p.to_str().map(String::from))
+            .unwrap_or_else(|| String::from("rem"));
+        let mut child = match std::process::Command::new(self.cmd)
+            .arg("--multi")
+            .arg(format!(
+                "--preview={} --info --idx {{1}}",
+                shell_quote(&rem)
+            ))
+            .arg("--preview-window=up")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(_) => {
+                eprintln!("{}", Error::ExecError(self.cmd));
+                return;
+            }
+        };
+        {
+            let mut stdin = child.stdin.take().unwrap();
+            for (i, e) in entries.contents.iter().enumerate() {
+                // The user may quit before the whole list is sent
+                if writeln!(stdin, "{} {}", i + 1, e.true_name()).is_err() {
+                    break;
+                }
+            }
+        }
+        let output = match child.wait_with_output() {
+            Ok(output) => output,
+            Err(_) => {
+                eprintln!("{}", Error::ExecError(self.cmd));
+                return;
+            }
+        };
+        // Aborting with Esc exits with a nonzero status and prints nothing,
+        // which naturally results in an empty selection.
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let idx = line.split(' ').next().and_then(|n| n.parse::<usize>().ok());
+            if let Some(i) = idx {
+                if 1 <= i && i <= entries.contents.len() {
+                    selection.insert((i, &entries.contents[i - 1]));
+                }
+            }
+        }
     }
 }
 impl Select for Block {
     fn select<'i>(&self, entries: &'i Entries, selection: &mut Selection<'i>) {
-        unimplemented!()
+        // `entries.blocks` holds the index of the first entry of each batch,
+        // followed by one past the last index.
+        let max = entries.blocks.len().saturating_sub(1);
+        for b in self.start.max(1)..=self.end.min(max) {
+            for i in entries.blocks[b - 1]..entries.blocks[b] {
+                selection.insert((i, &entries.contents[i - 1]));
+            }
+        }
     }
 }
 
@@ -209,3 +336,129 @@ impl Select for Selector {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HISTORY: &str = "
+a1|/foo/a1|100
+a2|/foo/a2|100
+
+b1|/bar/b1|200
+
+c1|/foo/c1|300
+c2|/bar/c2|300
+c3|/foo/c3|300
+";
+
+    fn root() -> Root {
+        Root::new(PathBuf::from("/trash"))
+    }
+
+    fn selected<S: Select>(sel: S) -> Vec<usize> {
+        let cfg = Config::detached(PathBuf::from("/trash"));
+        let mut storage = crate::storage::MemoryStorage::new();
+        storage.set_history(&root(), HISTORY.as_bytes());
+        let entries = Entries::load(&storage, &cfg).unwrap();
+        let mut selection = Selection::new();
+        sel.select(&entries, &mut selection);
+        selection.into_iter().map(|(i, _)| i).collect()
+    }
+
+    #[test]
+    fn parse_order() {
+        let entries = Entries::parse(HISTORY.as_bytes(), &root()).unwrap();
+        let aliases = entries
+            .contents
+            .iter()
+            .map(|e| e.alias.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(aliases, vec!["c3", "c2", "c1", "b1", "a2", "a1"]);
+        assert_eq!(entries.blocks, vec![1, 4, 5, 7]);
+    }
+
+    #[test]
+    fn merge_roots() {
+        let other = Root::new(PathBuf::from("/mnt/usb/.rem-trash-1000"));
+        let mounted = Entries::parse(
+            b"#rem-history 2
+x1|/mnt/usb/x1|150
+
+w1|/mnt/usb/w1|300
+
+y1|/mnt/usb/y1|400|400000000000
+",
+            &other,
+        )
+        .unwrap();
+        // The last batch was imported with the times of another trash
+        let main = Entries::parse(
+            b"#rem-history 2
+a1|/foo/a1|100
+
+b1|/foo/b1|300
+
+c1|/foo/c1|400|400000000000
+
+i1|/foo/i1|50|500000000000
+",
+            &root(),
+        )
+        .unwrap();
+        let entries = Entries::merge(vec![main, mounted]);
+        let aliases = entries
+            .contents
+            .iter()
+            .map(|e| e.alias.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(aliases, vec!["i1", "c1", "y1", "b1", "w1", "x1", "a1"]);
+        // Only batches of the same invocation are put together
+        assert_eq!(entries.blocks, vec![1, 2, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn quote() {
+        assert_eq!(shell_quote("/usr/bin/rem"), "'/usr/bin/rem'");
+        assert_eq!(shell_quote("/home/o'neil/rem"), "'/home/o'\\''neil/rem'");
+    }
+
+    #[test]
+    fn select_idx() {
+        assert_eq!(selected(Index::new(1, 1)), vec![1]);
+        assert_eq!(selected(Index::new(2, 4)), vec![2, 3, 4]);
+        assert_eq!(selected(Index::new(5, usize::MAX)), vec![5, 6]);
+        assert_eq!(selected(Index::new(7, 10)), vec![]);
+    }
+
+    #[test]
+    fn select_blk() {
+        assert_eq!(selected(Block::new(1, 1)), vec![1, 2, 3]);
+        assert_eq!(selected(Block::new(2, 3)), vec![4, 5, 6]);
+        assert_eq!(selected(Block::new(1, usize::MAX)), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(selected(Block::new(4, 4)), vec![]);
+        let empty = Entries::parse(b"", &root()).unwrap();
+        let mut selection = Selection::new();
+        Block::new(1, usize::MAX).select(&empty, &mut selection);
+        assert!(selection.is_empty());
+    }
+
+    #[test]
+    fn select_time() {
+        let young = Time::new(0, 150);
+        assert!(young.matches(400, 300));
+        assert!(!young.matches(400, 200));
+        let old = Time::new(150, u64::MAX);
+        assert!(old.matches(400, 200));
+        assert!(old.matches(400, 100));
+        assert!(!old.matches(400, 300));
+        // Clock skew must not make recent entries look infinitely old
+        assert!(young.matches(250, 300));
+    }
+
+    #[test]
+    fn select_pat() {
+        let re = regex::Regex::new("^/bar").unwrap();
+        assert_eq!(selected(Pattern::new(re)), vec![2, 4]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Guess the type of a file from its contents, in the spirit of `file(1)`
+//! but limited to the most common formats.
+
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Enough to reach the tar header and to judge whether text is text.
+const SAMPLE: usize = 8192;
+
+/// Leading bytes of formats that can be recognized without further parsing.
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x7fELF", "ELF"),
+    (b"%PDF-", "PDF document"),
+    (b"\x89PNG\r\n\x1a\n", "PNG image data"),
+    (b"\xff\xd8\xff", "JPEG image data"),
+    (b"GIF87a", "GIF image data"),
+    (b"GIF89a", "GIF image data"),
+    (b"\x1f\x8b", "gzip compressed data"),
+    (b"\xfd7zXZ\x00", "XZ compressed data"),
+    (b"\x28\xb5\x2f\xfd", "Zstandard compressed data"),
+    (b"PK\x03\x04", "Zip archive data"),
+    (b"PK\x05\x06", "Zip archive data (empty)"),
+    (b"SQLite format 3\x00", "SQLite 3.x database"),
+];
+
+/// Describe the contents of the regular file at `path`.
+pub fn sniff(path: &Path) -> io::Result<String> {
+    let mut sample = Vec::with_capacity(SAMPLE);
+    std::fs::File::open(path)?
+        .take(SAMPLE as u64)
+        .read_to_end(&mut sample)?;
+    Ok(describe(&sample))
+}
+
+pub fn describe(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "empty".to_string();
+    }
+    if let Some((_, name)) = MAGIC.iter().find(|(magic, _)| data.starts_with(magic)) {
+        if *name == "ELF" {
+            return elf(data);
+        }
+        return name.to_string();
+    }
+    if data.len() > 262 && &data[257..262] == b"ustar" {
+        return "POSIX tar archive".to_string();
+    }
+    match text(data) {
+        Some(text) => match interpreter(data) {
+            Some(cmd) => format!("{} script, {}", cmd, text),
+            None => text,
+        },
+        None => "data".to_string(),
+    }
+}
+
+fn elf(data: &[u8]) -> String {
+    let class = match data.get(4) {
+        Some(1) => "32-bit",
+        Some(2) => "64-bit",
+        _ => return "ELF".to_string(),
+    };
+    let little = data.get(5) == Some(&1);
+    let kind = match data.get(16..18) {
+        Some(&[a, b]) => {
+            let kind = if little {
+                u16::from_le_bytes([a, b])
+            } else {
+                u16::from_be_bytes([a, b])
+            };
+            match kind {
+                1 => "relocatable",
+                2 => "executable",
+                3 => "shared object",
+                4 => "core file",
+                _ => "file",
+            }
+        }
+        _ => "file",
+    };
+    format!(
+        "ELF {} {} {}",
+        class,
+        if little { "LSB" } else { "MSB" },
+        kind
+    )
+}
+
+/// Classify `data` as ASCII or UTF-8 text, along with its line endings.
+/// The sample may end in the middle of a multibyte character.
+fn text(data: &[u8]) -> Option<String> {
+    let valid = match std::str::from_utf8(data) {
+        Ok(s) => s,
+        Err(e) if e.error_len().is_none() && data.len() >= SAMPLE => {
+            std::str::from_utf8(&data[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return None,
+    };
+    let binary = valid
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c' | '\x1b'));
+    if binary {
+        return None;
+    }
+    let encoding = if valid.is_ascii() {
+        "ASCII text"
+    } else {
+        "UTF-8 text"
+    };
+    let crlf = valid.matches("\r\n").count();
+    let cr = valid.matches('\r').count() - crlf;
+    let lf = valid.matches('\n').count() - crlf;
+    let endings = match (lf > 0, crlf > 0, cr > 0) {
+        (_, true, false) if lf == 0 => Some("CRLF"),
+        (false, false, true) => Some("CR"),
+        (true, false, false) | (false, false, false) => None,
+        _ => Some("mixed"),
+    };
+    Some(match endings {
+        Some(endings) => format!("{}, with {} line terminators", encoding, endings),
+        None => encoding.to_string(),
+    })
+}
+
+/// Name of the program in a `#!` line, looking through `env`.
+fn interpreter(data: &[u8]) -> Option<String> {
+    let line = data.strip_prefix(b"#!")?;
+    let line = line.split(|&b| b == b'\n').next()?;
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.split_whitespace();
+    let mut cmd = words.next()?.rsplit('/').next()?;
+    if cmd == "env" {
+        cmd = words.find(|w| !w.starts_with('-'))?;
+    }
+    let cmd = cmd.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    Some(match cmd {
+        "sh" | "bash" | "dash" | "zsh" | "ksh" => "shell".to_string(),
+        "python" => "Python".to_string(),
+        "perl" => "Perl".to_string(),
+        "ruby" => "Ruby".to_string(),
+        "node" => "Node.js".to_string(),
+        other => other.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn magic() {
+        assert_eq!(describe(b""), "empty");
+        assert_eq!(describe(b"%PDF-1.7\n"), "PDF document");
+        assert_eq!(describe(b"\x89PNG\r\n\x1a\n\0\0"), "PNG image data");
+        assert_eq!(describe(b"\x1f\x8b\x08\0"), "gzip compressed data");
+        assert_eq!(describe(b"SQLite format 3\0..."), "SQLite 3.x database");
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(16, 0);
+        elf.extend_from_slice(&[3, 0]);
+        assert_eq!(describe(&elf), "ELF 64-bit LSB shared object");
+        let mut tar = vec![b'a'; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(describe(&tar), "POSIX tar archive");
+        assert_eq!(describe(b"\x00\x01\x02\x03"), "data");
+    }
+
+    #[test]
+    fn text_files() {
+        assert_eq!(describe(b"hello\nworld\n"), "ASCII text");
+        assert_eq!(
+            describe(b"hello\r\nworld\r\n"),
+            "ASCII text, with CRLF line terminators"
+        );
+        assert_eq!(
+            describe(b"a\nb\r\n"),
+            "ASCII text, with mixed line terminators"
+        );
+        assert_eq!(describe("\u{e9}t\u{e9}\n".as_bytes()), "UTF-8 text");
+        assert_eq!(
+            describe(b"#!/usr/bin/env python3\nprint()\n"),
+            "Python script, ASCII text"
+        );
+        assert_eq!(
+            describe(b"#!/bin/bash -e\nls\n"),
+            "shell script, ASCII text"
+        );
+    }
+}
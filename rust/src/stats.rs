@@ -0,0 +1,210 @@
+//! Summary of how much the trash holds, for `--stats`.
+
+use crate::meta::{format_date, human_size};
+use crate::select::{Entries, Entry, Selection};
+use crate::storage::Storage;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// How many of the largest entries and parent directories are kept
+const TOP: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Largest {
+    pub index: usize,
+    pub entry: Entry,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parent {
+    pub path: PathBuf,
+    pub entries: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub entries: usize,
+    pub batches: usize,
+    pub size: u64,
+    /// Removal times
+    pub oldest: Option<u64>,
+    pub newest: Option<u64>,
+    /// Biggest first
+    pub largest: Vec<Largest>,
+    /// Original parent directories, biggest first
+    pub parents: Vec<Parent>,
+}
+
+impl Stats {
+    /// Sizes are the ones recorded at removal, so the registry is only
+    /// walked for entries removed by older versions.
+    pub fn compute<S: Storage + ?Sized>(
+        storage: &S,
+        entries: &Entries,
+        selection: &Selection,
+    ) -> Self {
+        let mut stats = Self::default();
+        let mut batches = BTreeSet::new();
+        let mut largest = Vec::new();
+        let mut parents = BTreeMap::<&Path, (usize, u64)>::new();
+        for (idx, entry) in selection {
+            let size = storage.stored_size(&entry.root, &entry.alias);
+            stats.entries += 1;
+            stats.size += size;
+            batches.insert(entries.block_of(*idx));
+            stats.oldest = Some(
+                stats
+                    .oldest
+                    .map_or(entry.timestamp, |t| t.min(entry.timestamp)),
+            );
+            stats.newest = Some(
+                stats
+                    .newest
+                    .map_or(entry.timestamp, |t| t.max(entry.timestamp)),
+            );
+            largest.push(Largest {
+                index: *idx,
+                entry: (*entry).clone(),
+                size,
+            });
+            let parent = parents
+                .entry(entry.name.parent().unwrap_or(&entry.name))
+                .or_default();
+            parent.0 += 1;
+            parent.1 += size;
+        }
+        stats.batches = batches.len();
+        // Ties are broken by index, i.e. most recent first
+        largest.sort_by(|a, b| b.size.cmp(&a.size).then(a.index.cmp(&b.index)));
+        largest.truncate(TOP);
+        stats.largest = largest;
+        let mut parents = parents
+            .into_iter()
+            .map(|(path, (entries, size))| Parent {
+                path: path.to_path_buf(),
+                entries,
+                size,
+            })
+            .collect::<Vec<_>>();
+        parents.sort_by(|a, b| b.size.cmp(&a.size).then(a.path.cmp(&b.path)));
+        parents.truncate(TOP);
+        stats.parents = parents;
+        stats
+    }
+}
+
+fn tilde(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace(&std::env::var("HOME").unwrap_or("~".to_string()), "~")
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} entries in {} batches, {} in total",
+            self.entries,
+            self.batches,
+            human_size(self.size)
+        )?;
+        if let (Some(oldest), Some(newest)) = (self.oldest, self.newest) {
+            writeln!(
+                f,
+                "Removed between {} and {}",
+                format_date(oldest as i64, false),
+                format_date(newest as i64, false)
+            )?;
+        }
+        if !self.largest.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Largest entries")?;
+            for item in &self.largest {
+                writeln!(
+                    f,
+                    "  {:>5} {:>5} {}",
+                    item.index,
+                    human_size(item.size),
+                    item.entry.true_name()
+                )?;
+            }
+        }
+        if !self.parents.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "By original directory")?;
+            for parent in &self.parents {
+                writeln!(
+                    f,
+                    "  {:>5} {:>5} {}",
+                    human_size(parent.size),
+                    parent.entries,
+                    tilde(&parent.path)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Root;
+    use crate::select::{Index, Select};
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn summary() {
+        let root = Root::new(PathBuf::from("/trash"));
+        let mut storage = MemoryStorage::new();
+        let files = [("/a/x", 10, "x"), ("/a/y", 30, "y"), ("/b/z", 20, "z")];
+        for (name, size, _) in &files {
+            storage.insert(*name, &vec![0; *size]);
+        }
+        for (name, _, alias) in &files {
+            storage.store(&root, alias, Path::new(name), 0).unwrap();
+        }
+        storage.set_history(
+            &root,
+            b"#rem-history 2\n\nx|/a/x|100\ny|/a/y|200\n\nz|/b/z|300\n",
+        );
+        let cfg = crate::config::Config::detached(PathBuf::from("/trash"));
+        let entries = Entries::load(&storage, &cfg).unwrap();
+        let mut selection = Selection::new();
+        Index::new(1, 3).select(&entries, &mut selection);
+        let stats = Stats::compute(&storage, &entries, &selection);
+        assert_eq!((stats.entries, stats.batches, stats.size), (3, 2, 60));
+        assert_eq!((stats.oldest, stats.newest), (Some(100), Some(300)));
+        let largest = stats
+            .largest
+            .iter()
+            .map(|l| l.entry.alias.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(largest, vec!["y", "z", "x"]);
+        assert_eq!(
+            stats.parents,
+            vec![
+                Parent {
+                    path: PathBuf::from("/a"),
+                    entries: 2,
+                    size: 40
+                },
+                Parent {
+                    path: PathBuf::from("/b"),
+                    entries: 1,
+                    size: 20
+                },
+            ]
+        );
+        let mut selection = Selection::new();
+        Index::new(1, 1).select(&entries, &mut selection);
+        let stats = Stats::compute(&storage, &entries, &selection);
+        assert_eq!((stats.entries, stats.batches, stats.size), (1, 1, 20));
+        assert_eq!(
+            Stats::compute(&storage, &entries, &Selection::new()),
+            Stats::default()
+        );
+    }
+}
@@ -0,0 +1,661 @@
+//! Where removed files and their history are kept.
+//!
+//! `FsStorage` is the real thing: each root holds a `history` file and a
+//! `registry` directory with one `<alias>/{file,meta}` per entry.
+//! `MemoryStorage` keeps everything, including the files outside of the
+//! trash, in memory so that operations can be tested without touching the
+//! disk.
+
+use crate::command::Error;
+use crate::config::{Config, Root};
+use crate::journal::Op;
+use crate::lock::Lock;
+use crate::meta::Meta;
+use crate::select::Entry;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+pub trait Storage {
+    /// Take exclusive access to the trash for as long as the returned
+    /// value is alive, if the storage can be shared with other processes.
+    fn lock(&mut self, _cfg: &Config) -> Result<Option<Lock>, Error> {
+        Ok(None)
+    }
+
+    /// Whether something exists at `path`, outside of the trash.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Whether `path` is a directory outside of the trash, following
+    /// symlinks.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Create `dir` and its parents outside of the trash.
+    fn create_dir_all(&mut self, dir: &Path) -> Result<(), Error>;
+
+    /// Delete what is at `path` outside of the trash, to make room.
+    fn discard(&mut self, path: &Path) -> Result<(), Error>;
+
+    /// Name under which `store` should keep `file` in `root`, if the
+    /// storage has a preference. Random names are used otherwise.
+    fn alias_for(&self, _root: &Root, _file: &Path) -> Option<String> {
+        None
+    }
+
+    /// What `op` would do to the trash, for `--sandbox`.
+    fn describe(&self, op: &Op, entry: &Entry) -> Vec<String> {
+        let dir = entry.root.registry().join(&entry.alias);
+        let file = dir.join("file");
+        match op {
+            Op::Remove => vec![
+                format!("Create directory '{}'", dir.display()),
+                format!("Register data as '{}'", dir.join("meta").display()),
+                format!("Move '{}' to '{}'", entry.name.display(), file.display()),
+            ],
+            Op::Restore(dest) => vec![
+                format!("Move '{}' to '{}'", file.display(), dest.display()),
+                format!("Delete '{}'", dir.display()),
+            ],
+            Op::Purge => vec![format!("Delete '{}'", dir.display())],
+        }
+    }
+
+    /// Move `file` into `root` under `alias`, along with a description of
+    /// it recorded at `timestamp`.
+    fn store(&mut self, root: &Root, alias: &str, file: &Path, timestamp: u64)
+        -> Result<(), Error>;
+
+    /// Same as `store` for a file that was removed from `name` at
+    /// `timestamp` and has been kept at `file` since, e.g. by another trash.
+    fn import(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        file: &Path,
+        _name: &Path,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.store(root, alias, file, timestamp)
+    }
+
+    /// Whether `alias` is still stored in `root`.
+    fn contains(&self, root: &Root, alias: &str) -> bool;
+
+    /// Whether the file itself is stored under `alias`, and not only the
+    /// data about it.
+    fn intact(&self, root: &Root, alias: &str) -> bool;
+
+    /// Everything stored in `root`, whether the history knows of it or not.
+    fn aliases(&self, root: &Root) -> Vec<String>;
+
+    /// Contents of the `meta` file recorded by `store`.
+    fn meta(&self, root: &Root, alias: &str) -> Option<String>;
+
+    /// Size of what is at `path` outside of the trash, including
+    /// everything below it.
+    fn size_of(&self, path: &Path) -> u64;
+
+    /// Size of what is stored under `alias`, including everything below it.
+    fn stored_size(&self, root: &Root, alias: &str) -> u64;
+
+    /// Move the file stored under `alias` back out to `dest`.
+    /// Its data stays until `remove` is called.
+    fn retrieve(&mut self, root: &Root, alias: &str, dest: &Path) -> Result<(), Error>;
+
+    /// Where the file stored under `alias` is kept.
+    fn stored_file(&self, root: &Root, alias: &str) -> PathBuf {
+        root.registry().join(alias).join("file")
+    }
+
+    /// Where `inner` is kept, a path relative to the file stored under
+    /// `alias` that is empty for the file itself, if there is anything.
+    fn lookup(&self, root: &Root, alias: &str, inner: &Path) -> Result<PathBuf, Error> {
+        let path = within(self.stored_file(root, alias), inner);
+        match path.symlink_metadata() {
+            Ok(_) => Ok(path),
+            Err(_) => Err(Error::FileDoesNotExist(path.to_string_lossy().to_string())),
+        }
+    }
+
+    /// Open `inner`, found as by `lookup`, for reading. Only regular files
+    /// can be read, symlinks are followed.
+    fn read_stored(&self, root: &Root, alias: &str, inner: &Path) -> Result<Box<dyn Read>, Error> {
+        let path = self.lookup(root, alias, inner)?;
+        let lossy = path.to_string_lossy().to_string();
+        match std::fs::metadata(&path) {
+            Ok(meta) if !meta.is_file() => Err(Error::NotRegularFile(lossy)),
+            _ => match std::fs::File::open(&path) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(_) => Err(Error::FileDoesNotExist(lossy)),
+            },
+        }
+    }
+
+    /// Copy `inner`, found as by `lookup`, out to `dest` and leave what is
+    /// stored as it is.
+    fn copy_stored(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        inner: &Path,
+        dest: &Path,
+    ) -> Result<(), Error> {
+        let path = self.lookup(root, alias, inner)?;
+        crate::transfer::copy_path(&path, dest).map_err(|_| {
+            Error::CouldNotCopy(
+                path.to_string_lossy().to_string(),
+                dest.to_string_lossy().to_string(),
+            )
+        })
+    }
+
+    /// Forget everything stored under `alias`.
+    fn remove(&mut self, root: &Root, alias: &str) -> Result<(), Error>;
+
+    fn read_history(&self, root: &Root) -> Result<Vec<u8>, Error>;
+
+    /// Replace the history of `root` with `blocks`, oldest first.
+    fn write_history(&mut self, root: &Root, blocks: &[Vec<&Entry>]) -> Result<(), Error>;
+
+    /// Add a batch at the end of the history of `root`.
+    fn append_batch(&mut self, root: &Root, batch: &[&Entry]) -> Result<(), Error>;
+
+    /// Add `text` at the end of the log file `path`, creating it if needed.
+    /// The text must have reached the disk when this returns.
+    fn append_log(&mut self, path: &Path, text: &[u8]) -> Result<(), Error>;
+
+    /// Contents of the log file `path`, empty if it does not exist.
+    fn read_log(&self, path: &Path) -> Vec<u8>;
+
+    fn remove_log(&mut self, path: &Path) -> Result<(), Error>;
+}
+
+/// Report what could not be deleted from `file` once it was moved into the
+/// trash. The move itself succeeded, so this is not an error.
+pub(crate) fn leftover(file: &Path, moved: crate::transfer::Moved) {
+    if moved.leftover.is_some() {
+        eprintln!(
+            "{}",
+            Error::CouldNotRemove(file.to_string_lossy().to_string())
+        );
+    }
+}
+
+/// `inner` below `file`, without a trailing slash when it is empty.
+pub(crate) fn within(file: PathBuf, inner: &Path) -> PathBuf {
+    if inner.as_os_str().is_empty() {
+        file
+    } else {
+        file.join(inner)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsStorage;
+
+impl FsStorage {
+    fn registry(root: &Root, alias: &str) -> PathBuf {
+        root.registry().join(alias)
+    }
+}
+
+impl Storage for FsStorage {
+    /// Also converts histories written by older versions.
+    fn lock(&mut self, cfg: &Config) -> Result<Option<Lock>, Error> {
+        let lock = Lock::acquire(cfg.lock())?;
+        for root in cfg.roots() {
+            // A history that cannot be converted is left as is, reading it
+            // reports the problem and `--fsck --repair` can fix it.
+            if let Ok(true) = crate::history::migrate(root) {
+                eprintln!(
+                    "Converted '{}' to history format version {}",
+                    root.history().display(),
+                    crate::history::VERSION
+                );
+            }
+        }
+        Ok(Some(lock))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.symlink_metadata().is_ok()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn create_dir_all(&mut self, dir: &Path) -> Result<(), Error> {
+        std::fs::create_dir_all(dir)
+            .map_err(|_| Error::CouldNotCreateDir(dir.to_string_lossy().to_string()))
+    }
+
+    fn discard(&mut self, path: &Path) -> Result<(), Error> {
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+        removed.map_err(|_| Error::CouldNotRemove(path.to_string_lossy().to_string()))
+    }
+
+    fn store(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        file: &Path,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.import(root, alias, file, file, timestamp)
+    }
+
+    fn import(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        file: &Path,
+        name: &Path,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let destination = Self::registry(root, alias);
+        let destfile = destination.join("file");
+        let destdata = destination.join("meta");
+        std::fs::create_dir(&destination)
+            .map_err(|_| Error::CouldNotCreateDir(destination.to_string_lossy().to_string()))?;
+        Meta::collect(file, timestamp)
+            .and_then(|mut meta| {
+                meta.name = name.to_path_buf();
+                meta.write(&destdata)
+            })
+            .map_err(|_| {
+                let _ = std::fs::remove_dir_all(&destination);
+                Error::FailedToWrite(destdata.to_string_lossy().to_string(), None)
+            })?;
+        let moved = crate::transfer::move_path(file, &destfile).map_err(|_| {
+            let _ = std::fs::remove_dir_all(&destination);
+            Error::CouldNotMove(
+                file.to_string_lossy().to_string(),
+                destfile.to_string_lossy().to_string(),
+            )
+        })?;
+        leftover(file, moved);
+        Ok(())
+    }
+
+    fn contains(&self, root: &Root, alias: &str) -> bool {
+        Self::registry(root, alias).exists()
+    }
+
+    fn intact(&self, root: &Root, alias: &str) -> bool {
+        self.exists(&Self::registry(root, alias).join("file"))
+    }
+
+    fn aliases(&self, root: &Root) -> Vec<String> {
+        let mut aliases = std::fs::read_dir(root.registry())
+            .map(|dir| {
+                dir.filter_map(Result::ok)
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        aliases.sort();
+        aliases
+    }
+
+    fn meta(&self, root: &Root, alias: &str) -> Option<String> {
+        std::fs::read_to_string(Self::registry(root, alias).join("meta")).ok()
+    }
+
+    fn size_of(&self, path: &Path) -> u64 {
+        crate::meta::total_size(path)
+    }
+
+    /// Recorded at removal, only entries removed by older versions need
+    /// to be walked.
+    fn stored_size(&self, root: &Root, alias: &str) -> u64 {
+        self.meta(root, alias)
+            .and_then(|text| Meta::parse(&text))
+            .and_then(|meta| meta.total)
+            .unwrap_or_else(|| crate::meta::total_size(&Self::registry(root, alias).join("file")))
+    }
+
+    fn retrieve(&mut self, root: &Root, alias: &str, dest: &Path) -> Result<(), Error> {
+        let file = Self::registry(root, alias).join("file");
+        // Whatever is left in the registry goes along with it
+        let moved = crate::transfer::move_path(&file, dest).is_ok() && self.exists(dest);
+        if !moved {
+            return Err(Error::CouldNotMove(
+                file.to_string_lossy().to_string(),
+                dest.to_string_lossy().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, root: &Root, alias: &str) -> Result<(), Error> {
+        let dir = Self::registry(root, alias);
+        std::fs::remove_dir_all(&dir)
+            .map_err(|_| Error::CouldNotRemove(dir.to_string_lossy().to_string()))
+    }
+
+    fn read_history(&self, root: &Root) -> Result<Vec<u8>, Error> {
+        let file = root.history();
+        std::fs::read(file)
+            .map_err(|_| Error::HistoryNotReadable(file.to_string_lossy().to_string()))
+    }
+
+    fn write_history(&mut self, root: &Root, blocks: &[Vec<&Entry>]) -> Result<(), Error> {
+        crate::history::write(root, blocks)
+    }
+
+    fn append_batch(&mut self, root: &Root, batch: &[&Entry]) -> Result<(), Error> {
+        crate::history::append(root, batch)
+    }
+
+    fn append_log(&mut self, path: &Path, text: &[u8]) -> Result<(), Error> {
+        use std::io::Write;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(text).and_then(|_| f.sync_data()))
+            .map_err(|_| Error::FailedToWrite(path.to_string_lossy().to_string(), None))
+    }
+
+    fn read_log(&self, path: &Path) -> Vec<u8> {
+        std::fs::read(path).unwrap_or_default()
+    }
+
+    fn remove_log(&mut self, path: &Path) -> Result<(), Error> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::CouldNotRemove(path.to_string_lossy().to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Regular files by path. Directories only exist implicitly, as the
+/// ancestors of files.
+type Tree = BTreeMap<PathBuf, Vec<u8>>;
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Tree,
+    /// Stored trees, with paths relative to the removed file
+    blobs: BTreeMap<(PathBuf, String), Tree>,
+    histories: BTreeMap<PathBuf, Vec<u8>>,
+    logs: Tree,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a file outside of the trash.
+    pub fn insert<P: Into<PathBuf>>(&mut self, path: P, contents: &[u8]) {
+        self.files.insert(path.into(), contents.to_vec());
+    }
+
+    /// Contents of a file outside of the trash.
+    pub fn file(&self, path: &Path) -> Option<&[u8]> {
+        self.files.get(path).map(Vec::as_slice)
+    }
+
+    /// Contents of a log written by `append_log`.
+    pub fn log(&self, path: &Path) -> Option<&[u8]> {
+        self.logs.get(path).map(Vec::as_slice)
+    }
+
+    pub fn set_history(&mut self, root: &Root, contents: &[u8]) {
+        self.histories
+            .insert(root.root().to_path_buf(), contents.to_vec());
+    }
+
+    fn key(root: &Root, alias: &str) -> (PathBuf, String) {
+        (root.root().to_path_buf(), alias.to_string())
+    }
+
+    /// Take out `path` and everything below it.
+    fn take(&mut self, path: &Path) -> Tree {
+        let (taken, kept) = std::mem::take(&mut self.files)
+            .into_iter()
+            .partition::<Tree, _>(|(f, _)| f.starts_with(path));
+        self.files = kept;
+        taken
+            .into_iter()
+            .map(|(f, data)| (f.strip_prefix(path).unwrap().to_path_buf(), data))
+            .collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn exists(&self, path: &Path) -> bool {
+        self.files.keys().any(|f| f.starts_with(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files.keys().any(|f| f.starts_with(path) && f != path)
+    }
+
+    fn create_dir_all(&mut self, _dir: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn discard(&mut self, path: &Path) -> Result<(), Error> {
+        self.take(path);
+        Ok(())
+    }
+
+    fn store(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        file: &Path,
+        _timestamp: u64,
+    ) -> Result<(), Error> {
+        if !self.exists(file) {
+            return Err(Error::FileDoesNotExist(file.to_string_lossy().to_string()));
+        }
+        let tree = self.take(file);
+        self.blobs.insert(Self::key(root, alias), tree);
+        Ok(())
+    }
+
+    fn contains(&self, root: &Root, alias: &str) -> bool {
+        self.blobs.contains_key(&Self::key(root, alias))
+    }
+
+    /// Retrieved trees leave an empty one behind until removed.
+    fn intact(&self, root: &Root, alias: &str) -> bool {
+        self.blobs
+            .get(&Self::key(root, alias))
+            .is_some_and(|tree| !tree.is_empty())
+    }
+
+    fn aliases(&self, root: &Root) -> Vec<String> {
+        self.blobs
+            .keys()
+            .filter(|(r, _)| r == root.root())
+            .map(|(_, alias)| alias.clone())
+            .collect()
+    }
+
+    fn meta(&self, _root: &Root, _alias: &str) -> Option<String> {
+        None
+    }
+
+    fn size_of(&self, path: &Path) -> u64 {
+        self.files
+            .iter()
+            .filter(|(f, _)| f.starts_with(path))
+            .map(|(_, data)| data.len() as u64)
+            .sum()
+    }
+
+    fn stored_size(&self, root: &Root, alias: &str) -> u64 {
+        self.blobs
+            .get(&Self::key(root, alias))
+            .map(|tree| tree.values().map(|data| data.len() as u64).sum())
+            .unwrap_or(0)
+    }
+
+    fn retrieve(&mut self, root: &Root, alias: &str, dest: &Path) -> Result<(), Error> {
+        let tree = self
+            .blobs
+            .get_mut(&Self::key(root, alias))
+            .map(std::mem::take)
+            .ok_or_else(|| {
+                Error::CouldNotMove(alias.to_string(), dest.to_string_lossy().to_string())
+            })?;
+        for (rel, data) in tree {
+            let path = if rel.as_os_str().is_empty() {
+                dest.to_path_buf()
+            } else {
+                dest.join(rel)
+            };
+            self.files.insert(path, data);
+        }
+        Ok(())
+    }
+
+    /// Paths are relative to the stored tree.
+    fn lookup(&self, root: &Root, alias: &str, inner: &Path) -> Result<PathBuf, Error> {
+        let tree = self.blobs.get(&Self::key(root, alias));
+        if tree.is_some_and(|tree| tree.keys().any(|f| f.starts_with(inner))) {
+            Ok(within(self.stored_file(root, alias), inner))
+        } else {
+            Err(Error::FileDoesNotExist(inner.to_string_lossy().to_string()))
+        }
+    }
+
+    fn read_stored(&self, root: &Root, alias: &str, inner: &Path) -> Result<Box<dyn Read>, Error> {
+        let path = self.lookup(root, alias, inner)?;
+        match self.blobs[&Self::key(root, alias)].get(inner) {
+            Some(data) => Ok(Box::new(std::io::Cursor::new(data.clone()))),
+            None => Err(Error::NotRegularFile(path.to_string_lossy().to_string())),
+        }
+    }
+
+    fn copy_stored(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        inner: &Path,
+        dest: &Path,
+    ) -> Result<(), Error> {
+        self.lookup(root, alias, inner)?;
+        let copies = self.blobs[&Self::key(root, alias)]
+            .iter()
+            .filter_map(|(rel, data)| {
+                let rel = rel.strip_prefix(inner).ok()?;
+                Some((within(dest.to_path_buf(), rel), data.clone()))
+            })
+            .collect::<Vec<_>>();
+        self.files.extend(copies);
+        Ok(())
+    }
+
+    fn remove(&mut self, root: &Root, alias: &str) -> Result<(), Error> {
+        self.blobs.remove(&Self::key(root, alias));
+        Ok(())
+    }
+
+    fn read_history(&self, root: &Root) -> Result<Vec<u8>, Error> {
+        Ok(self.histories.get(root.root()).cloned().unwrap_or_default())
+    }
+
+    fn write_history(&mut self, root: &Root, blocks: &[Vec<&Entry>]) -> Result<(), Error> {
+        let contents = crate::history::format(blocks);
+        self.set_history(root, contents.as_bytes());
+        Ok(())
+    }
+
+    fn append_batch(&mut self, root: &Root, batch: &[&Entry]) -> Result<(), Error> {
+        let history = self.histories.entry(root.root().to_path_buf()).or_default();
+        let contents = crate::history::format_batch(batch, history.is_empty());
+        history.extend_from_slice(contents.as_bytes());
+        Ok(())
+    }
+
+    fn append_log(&mut self, path: &Path, text: &[u8]) -> Result<(), Error> {
+        self.logs
+            .entry(path.to_path_buf())
+            .or_default()
+            .extend_from_slice(text);
+        Ok(())
+    }
+
+    fn read_log(&self, path: &Path) -> Vec<u8> {
+        self.logs.get(path).cloned().unwrap_or_default()
+    }
+
+    fn remove_log(&mut self, path: &Path) -> Result<(), Error> {
+        self.logs.remove(path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::select::Entries;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn restore_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new("storage");
+        let cfg = Config::new(dir.join("trash")).unwrap();
+        let root = cfg.roots().next().unwrap().clone();
+        let mut storage = FsStorage;
+        let name = dir.join(OsStr::from_bytes(b"caf\xe9|\n"));
+        std::fs::write(&name, "contents").unwrap();
+        let entry = Entry {
+            name: name.clone(),
+            alias: String::from("alias"),
+            timestamp: 100,
+            root: root.clone(),
+            batch: None,
+        };
+        storage.store(&root, "alias", &name, 100).unwrap();
+        assert!(!storage.exists(&name));
+        assert!(Meta::parse(&storage.meta(&root, "alias").unwrap()).is_some());
+        storage.append_batch(&root, &[&entry]).unwrap();
+        let entries = Entries::load(&storage, &cfg).unwrap();
+        assert_eq!(entries.get(1), Some(&entry));
+        storage.retrieve(&root, "alias", &name).unwrap();
+        storage.remove(&root, "alias").unwrap();
+        assert_eq!(std::fs::read_to_string(&name).unwrap(), "contents");
+        assert!(!storage.contains(&root, "alias"));
+    }
+
+    #[test]
+    fn memory_trees() {
+        let root = Root::new(PathBuf::from("/trash"));
+        let mut storage = MemoryStorage::new();
+        storage.insert("/home/dir/a", b"a");
+        storage.insert("/home/dir/sub/b", b"b");
+        storage.insert("/home/other", b"c");
+        assert_eq!(storage.size_of(Path::new("/home/dir")), 2);
+        storage
+            .store(&root, "x", Path::new("/home/dir"), 0)
+            .unwrap();
+        assert_eq!(storage.stored_size(&root, "x"), 2);
+        assert!(!storage.exists(Path::new("/home/dir")));
+        assert!(storage.exists(Path::new("/home/other")));
+        storage
+            .retrieve(&root, "x", Path::new("/home/dir.0"))
+            .unwrap();
+        assert_eq!(
+            storage.file(Path::new("/home/dir.0/sub/b")),
+            Some(&b"b"[..])
+        );
+        assert!(storage
+            .store(&root, "y", Path::new("/home/missing"), 0)
+            .is_err());
+    }
+}
@@ -0,0 +1,276 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Trees larger than this report their progress while being copied.
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// A move that succeeded, but may have left part of the original behind.
+#[derive(Debug)]
+#[must_use]
+pub struct Moved {
+    /// Why the original could not be completely deleted once copied
+    pub leftover: Option<io::Error>,
+}
+
+/// Move `src` to `dest`, which must not exist yet.
+///
+/// This is a plain rename whenever possible. When both paths are on
+/// different filesystems the tree is instead copied (preserving permissions,
+/// symlinks and timestamps), checked against the original, and only then
+/// is the original deleted. From then on `dest` is the only complete copy,
+/// so failing to delete the original does not fail the move.
+pub fn move_path(src: &Path, dest: &Path) -> io::Result<Moved> {
+    match std::fs::rename(src, dest) {
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => (),
+        res => return res.map(|_| Moved { leftover: None }),
+    }
+    let total = tree_size(src)?;
+    let mut progress = Progress::new(src, total);
+    let copied = copy_tree(src, dest, &mut progress).and_then(|_| verify(src, dest));
+    progress.finish();
+    if let Err(e) = copied {
+        let _ = remove_tree(dest);
+        return Err(e);
+    }
+    Ok(Moved {
+        leftover: remove_tree(src).err(),
+    })
+}
+
+/// Copy `src` to `dest`, which must not exist yet, leaving `src` as it is.
+/// Permissions, symlinks and timestamps are preserved as when moving
+/// across filesystems, and nothing is left at `dest` if the copy fails.
+pub fn copy_path(src: &Path, dest: &Path) -> io::Result<()> {
+    let total = tree_size(src)?;
+    let mut progress = Progress::new(src, total);
+    let copied = copy_tree(src, dest, &mut progress);
+    progress.finish();
+    if copied.is_err() && dest.symlink_metadata().is_ok() {
+        let _ = remove_tree(dest);
+    }
+    copied
+}
+
+fn remove_tree(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+fn tree_size(path: &Path) -> io::Result<u64> {
+    let meta = path.symlink_metadata()?;
+    if meta.is_dir() {
+        let mut total = 0;
+        for entry in std::fs::read_dir(path)? {
+            total += tree_size(&entry?.path())?;
+        }
+        Ok(total)
+    } else {
+        Ok(meta.len())
+    }
+}
+
+fn copy_tree(src: &Path, dest: &Path, progress: &mut Progress) -> io::Result<()> {
+    let meta = src.symlink_metadata()?;
+    let ft = meta.file_type();
+    if ft.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(src)?, dest)?;
+    } else if ft.is_dir() {
+        std::fs::create_dir(dest)?;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()), progress)?;
+        }
+        std::fs::set_permissions(dest, std::fs::Permissions::from_mode(meta.mode()))?;
+    } else if ft.is_file() {
+        std::fs::copy(src, dest)?;
+        std::fs::set_permissions(dest, std::fs::Permissions::from_mode(meta.mode()))?;
+        progress.advance(meta.len());
+    } else {
+        return Err(io::Error::other(format!(
+            "cannot copy special file '{}'",
+            src.to_string_lossy()
+        )));
+    }
+    // Ownership can only be kept when running with enough privileges,
+    // failing to do so is not an error.
+    let _ = lchown(dest, meta.uid(), meta.gid());
+    // Set last, since filling a directory updates its timestamps
+    set_times(dest, &meta)
+}
+
+/// Check that `dest` is a faithful copy of `src`: same structure, same
+/// file types, same contents and same symlink targets.
+fn verify(src: &Path, dest: &Path) -> io::Result<()> {
+    let mismatch = || {
+        io::Error::other(format!(
+            "copy of '{}' does not match the original",
+            src.to_string_lossy()
+        ))
+    };
+    let s = src.symlink_metadata()?;
+    let d = dest.symlink_metadata()?;
+    if s.file_type() != d.file_type() {
+        return Err(mismatch());
+    }
+    if s.file_type().is_symlink() {
+        if std::fs::read_link(src)? != std::fs::read_link(dest)? {
+            return Err(mismatch());
+        }
+    } else if s.is_dir() {
+        let mut count = 0;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            verify(&entry.path(), &dest.join(entry.file_name()))?;
+            count += 1;
+        }
+        if std::fs::read_dir(dest)?.count() != count {
+            return Err(mismatch());
+        }
+    } else if s.len() != d.len() || !same_contents(src, dest)? {
+        return Err(mismatch());
+    }
+    Ok(())
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::io::Read;
+    let (mut a, mut b) = (std::fs::File::open(a)?, std::fs::File::open(b)?);
+    let (mut abuf, mut bbuf) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let n = a.read(&mut abuf)?;
+        if n == 0 {
+            // Same sizes, unless one of them is being written to
+            return Ok(b.read(&mut bbuf)? == 0);
+        }
+        b.read_exact(&mut bbuf[..n])?;
+        if abuf[..n] != bbuf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let path = c_path(path)?;
+    if unsafe { libc::lchown(path.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn set_times(path: &Path, meta: &std::fs::Metadata) -> io::Result<()> {
+    let path = c_path(path)?;
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime() as libc::time_t,
+            tv_nsec: meta.atime_nsec() as libc::c_long,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime() as libc::time_t,
+            tv_nsec: meta.mtime_nsec() as libc::c_long,
+        },
+    ];
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+struct Progress {
+    name: String,
+    total: u64,
+    done: u64,
+    shown: u64,
+}
+
+impl Progress {
+    fn new(src: &Path, total: u64) -> Self {
+        Self {
+            name: src.to_string_lossy().to_string(),
+            total,
+            done: 0,
+            shown: 0,
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.total >= PROGRESS_THRESHOLD
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        let percent = self.done * 100 / self.total.max(1);
+        if self.active() && percent > self.shown {
+            self.shown = percent;
+            eprint!(
+                "\rCopying '{}': {}% of {}",
+                self.name,
+                percent,
+                crate::meta::human_size(self.total)
+            );
+        }
+    }
+
+    fn finish(&self) {
+        if self.active() {
+            eprintln!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn copy_preserves_tree() {
+        let root = TempDir::new("transfer");
+        let src = root.join("src");
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("sub/data"), "contents").unwrap();
+        std::fs::set_permissions(src.join("sub/data"), std::fs::Permissions::from_mode(0o640))
+            .unwrap();
+        std::os::unix::fs::symlink("sub/data", src.join("link")).unwrap();
+        let dest = root.join("dest");
+        let mut progress = Progress::new(&src, tree_size(&src).unwrap());
+        copy_tree(&src, &dest, &mut progress).unwrap();
+        verify(&src, &dest).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dest.join("sub/data")).unwrap(),
+            "contents"
+        );
+        assert_eq!(
+            std::fs::read_link(dest.join("link")).unwrap(),
+            Path::new("sub/data")
+        );
+        let (s, d) = (
+            src.join("sub/data").metadata().unwrap(),
+            dest.join("sub/data").metadata().unwrap(),
+        );
+        assert_eq!(d.mode() & 0o777, 0o640);
+        assert_eq!((s.mtime(), s.mtime_nsec()), (d.mtime(), d.mtime_nsec()));
+        std::fs::write(dest.join("sub/data"), "CONTENTS").unwrap();
+        assert!(verify(&src, &dest).is_err());
+    }
+}
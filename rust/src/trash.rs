@@ -0,0 +1,1610 @@
+//! High-level operations on the trash, shared by the command line and by
+//! programs that use `rem` as a library.
+//!
+//! ```no_run
+//! use rem::{Config, Select, Trash};
+//!
+//! let mut trash = Trash::new(Config::getenv()?);
+//! trash.remove(&["notes.txt"]);
+//! let entries = trash.entries().unwrap();
+//! let mut selection = rem::Selection::new();
+//! rem::select::Index::new(1, 1).select(&entries, &mut selection);
+//! trash.restore(&selection, rem::RestoreOptions::default());
+//! # Ok::<(), rem::Error>(())
+//! ```
+
+use crate::command::Error;
+use crate::config::{Config, Root};
+use crate::fsck::{Check, Issue};
+use crate::journal::{Intent, Op};
+use crate::lock::Lock;
+use crate::meta::Meta;
+use crate::select::{Entries, Entry, Select, Selection};
+use crate::stats::Stats;
+use crate::storage::{within, FsStorage, Storage};
+use crate::xdg::XdgStorage;
+use std::io::{ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Per-item results of an operation on several files, in the order in
+/// which the files were given, along with the errors that concern the
+/// operation as a whole (e.g. the history could not be updated).
+#[derive(Debug)]
+pub struct Results<T> {
+    pub items: Vec<Result<T, Error>>,
+    pub errors: Vec<Error>,
+}
+
+impl<T> Results<T> {
+    fn failed(err: Error) -> Self {
+        Self {
+            items: Vec::new(),
+            errors: vec![err],
+        }
+    }
+}
+
+impl<T> Default for Results<T> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// Outcome of `Trash::remove`.
+#[derive(Debug, Default)]
+pub struct Removal {
+    /// One item per file to remove
+    pub removed: Results<Entry>,
+    /// Older entries that were purged to stay within the quota
+    pub evicted: Results<Entry>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Replace files that exist at the original location instead of
+    /// restoring next to them.
+    pub overwrite: bool,
+    /// Restore into this directory instead of the original location, or
+    /// as this path if a single entry is restored and it is not a directory.
+    pub to: Option<PathBuf>,
+    /// Recreate the whole original path of the entries inside `to`.
+    pub parents: bool,
+}
+
+#[derive(Debug)]
+pub struct Trash<S: Storage = FsStorage> {
+    cfg: Config,
+    storage: S,
+    sandbox: bool,
+    /// Whether the plans of sandbox mode go to stderr
+    plans_on_stderr: bool,
+    /// Whether the journal holds intents of the current operation
+    journaled: bool,
+    /// Identifies the batches recorded by the current operation in every
+    /// root
+    batch: u64,
+}
+
+impl Trash {
+    pub fn new(cfg: Config) -> Self {
+        Self::with_storage(cfg, FsStorage)
+    }
+}
+
+impl<S: Storage> Trash<S> {
+    pub fn with_storage(cfg: Config, storage: S) -> Self {
+        Self {
+            cfg,
+            storage,
+            sandbox: false,
+            plans_on_stderr: false,
+            journaled: false,
+            batch: 0,
+        }
+    }
+
+    /// In sandbox mode operations print what they would do instead.
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Print what sandbox mode would do on stderr, so that stdout only
+    /// holds the records of a machine-readable format.
+    pub fn plans_on_stderr(mut self, stderr: bool) -> Self {
+        self.plans_on_stderr = stderr;
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Exclusive access to the trash for the duration of an operation.
+    /// Released when dropped, including when unwinding from a panic.
+    fn lock(&mut self) -> Result<Option<Lock>, Error> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        self.batch = now.max(self.batch + 1);
+        if self.sandbox {
+            return Ok(None);
+        }
+        let lock = self.storage.lock(&self.cfg)?;
+        // Nothing else may happen to the files of an interrupted operation
+        // before it is dealt with, but failing to do so only concerns them.
+        if let Err(err) = self.replay() {
+            eprintln!("{}", err);
+        }
+        Ok(lock)
+    }
+
+    /// Record in the journal what is about to be done to `entry`.
+    fn intend(&mut self, op: Op, entry: &Entry) -> Result<(), Error> {
+        if self.sandbox {
+            return Ok(());
+        }
+        let mut text = String::new();
+        if !self.journaled {
+            text.push_str(crate::journal::HEADER);
+            text.push('\n');
+        }
+        text.push_str(&Intent::new(op, entry).serialize());
+        self.storage
+            .append_log(self.cfg.journal(), text.as_bytes())?;
+        self.journaled = true;
+        Ok(())
+    }
+
+    /// Print a step of what sandbox mode would do.
+    fn say(&self, line: std::fmt::Arguments) {
+        if self.plans_on_stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    /// Root in which to store `file`, see `Config::root_for`.
+    fn root_for(&mut self, file: &Path) -> Root {
+        let root = self.cfg.root_for(file, self.sandbox);
+        if self.sandbox && !self.cfg.roots().any(|known| *known == root) {
+            self.say(format_args!(
+                "Create trash root '{}'",
+                root.root().display()
+            ));
+        }
+        root
+    }
+
+    /// Print what `op` would do, in sandbox mode.
+    fn plan(&self, op: &Op, entry: &Entry) {
+        for line in self.storage.describe(op, entry) {
+            self.say(format_args!("{}", line));
+        }
+    }
+
+    /// The current operation is complete, histories included.
+    fn commit(&mut self) -> Result<(), Error> {
+        if !self.journaled {
+            return Ok(());
+        }
+        self.storage.remove_log(self.cfg.journal())?;
+        self.journaled = false;
+        Ok(())
+    }
+
+    /// Deal with the operations of an invocation that was interrupted, if
+    /// any. Every operation that modifies the trash does so first, this is
+    /// for those that only read it.
+    pub fn recover(&mut self) -> Result<(), Error> {
+        if self.sandbox || self.storage.read_log(self.cfg.journal()).is_empty() {
+            return Ok(());
+        }
+        self.lock().map(|_| ())
+    }
+
+    /// Complete or roll back the operations of an invocation that was
+    /// interrupted, according to its journal and to where the files
+    /// actually are.
+    fn replay(&mut self) -> Result<(), Error> {
+        let journal = self.cfg.journal().to_path_buf();
+        let contents = self.storage.read_log(&journal);
+        if contents.is_empty() {
+            return Ok(());
+        }
+        let recorded = self
+            .entries()?
+            .iter()
+            .map(|(_, e)| (e.root.clone(), e.alias.clone()))
+            .collect::<std::collections::BTreeSet<_>>();
+        let mut adopted = Vec::new();
+        for mut intent in crate::journal::parse(&contents) {
+            // The journal only says where the root is, not what kind it is
+            if let Some(root) = self.cfg.roots().find(|root| **root == intent.entry.root) {
+                intent.entry.root = root.clone();
+            }
+            let entry = &intent.entry;
+            let (root, alias) = (&entry.root, entry.alias.as_str());
+            let stored = self.storage.contains(root, alias);
+            let intact = self.storage.intact(root, alias);
+            let name = entry.name.display();
+            match &intent.op {
+                Op::Remove if recorded.contains(&(root.clone(), alias.to_string())) => (),
+                // A move between filesystems copies before deleting, there
+                // is no telling which of the two is incomplete.
+                Op::Remove if intact && self.storage.exists(&entry.name) => {
+                    eprintln!(
+                        "Interrupted while moving '{}' to the trash, both copies were kept",
+                        name
+                    );
+                    adopted.push(entry.clone());
+                }
+                Op::Remove if intact => {
+                    eprintln!("Completing interrupted removal of '{}'", name);
+                    adopted.push(entry.clone());
+                }
+                Op::Remove if stored => {
+                    eprintln!("Rolling back interrupted removal of '{}'", name);
+                    self.storage.remove(root, alias)?;
+                }
+                Op::Restore(dest) if intact && self.storage.exists(dest) => {
+                    eprintln!(
+                        "Interrupted while restoring '{}', both copies were kept: '{}' may be incomplete",
+                        name,
+                        dest.display()
+                    );
+                }
+                Op::Restore(dest) if stored && !intact => {
+                    eprintln!(
+                        "Completing interrupted restoration of '{}' to '{}'",
+                        name,
+                        dest.display()
+                    );
+                    self.storage.remove(root, alias)?;
+                }
+                Op::Purge if stored => {
+                    eprintln!("Completing interrupted deletion of '{}'", name);
+                    self.storage.remove(root, alias)?;
+                }
+                _ => (),
+            }
+        }
+        let roots = self.cfg.roots().cloned().collect::<Vec<_>>();
+        for root in &roots {
+            let batch = adopted
+                .iter()
+                .filter(|e| e.root == *root)
+                .collect::<Vec<_>>();
+            if !batch.is_empty() {
+                let batch = self.stamp(&batch);
+                self.storage
+                    .append_batch(root, &batch.iter().collect::<Vec<_>>())?;
+            }
+        }
+        if let Some(err) = self.clean_history().into_iter().next() {
+            return Err(err);
+        }
+        self.storage.remove_log(&journal)
+    }
+
+    /// Move `paths` to the trash as a single batch, making room for them
+    /// first if the trash has a quota.
+    pub fn remove<I, P>(&mut self, paths: I) -> Removal
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let refused = |err| Removal {
+            removed: Results::failed(err),
+            evicted: Results::default(),
+        };
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return refused(err),
+        };
+        let paths = match paths
+            .into_iter()
+            .map(|path| absolute(path.as_ref()))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(paths) => paths,
+            Err(err) => return refused(err),
+        };
+        let evicted = match self.cfg.quota() {
+            Some(quota) => match self.make_room(&paths, quota) {
+                Ok(evicted) => evicted,
+                Err(err) => return refused(err),
+            },
+            None => Results::default(),
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let items = paths
+            .iter()
+            .map(|path| self.remove_one(path, timestamp))
+            .collect::<Vec<_>>();
+        let mut errors = self.register(&items);
+        errors.extend(self.commit().err());
+        Removal {
+            removed: Results { items, errors },
+            evicted,
+        }
+    }
+
+    /// Record the entries that were stored as a new batch of their root.
+    fn register(&mut self, items: &[Result<Entry, Error>]) -> Vec<Error> {
+        let register = items
+            .iter()
+            .filter_map(|res| res.as_ref().ok())
+            .collect::<Vec<_>>();
+        let roots = register
+            .iter()
+            .map(|e| &e.root)
+            .collect::<std::collections::BTreeSet<_>>();
+        let mut errors = Vec::new();
+        for root in roots {
+            let batch = register
+                .iter()
+                .copied()
+                .filter(|e| e.root == *root)
+                .collect::<Vec<_>>();
+            if let Err(err) = self.append_batch(root, &batch) {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+
+    /// Move everything from the FreeDesktop.org trash `trash` into this one,
+    /// as a single batch that keeps the original removal times, oldest
+    /// first. Items that do not say where they came from are left there.
+    pub fn import_xdg(&mut self, trash: &Path) -> Results<Entry> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return Results::failed(err),
+        };
+        let source = Root::xdg(trash.to_path_buf());
+        if let Err(err) = self.foreign(&source) {
+            return Results::failed(err);
+        }
+        let xdg = XdgStorage;
+        let found = match xdg
+            .read_history(&source)
+            .and_then(|history| crate::history::parse(&history, &source))
+        {
+            Ok(blocks) => blocks.into_iter().flatten().collect::<Vec<_>>(),
+            Err(err) => return Results::failed(err),
+        };
+        let mut items = found
+            .iter()
+            .map(|entry| {
+                let file = XdgStorage::file(&entry.root, &entry.alias);
+                let info = XdgStorage::info(&entry.root, &entry.alias);
+                self.take_over(entry, file, info, None)
+            })
+            .collect::<Vec<_>>();
+        for alias in xdg.aliases(&source) {
+            if xdg.intact(&source, &alias) && !found.iter().any(|e| e.alias == alias) {
+                let info = XdgStorage::info(&source, &alias);
+                items.push(Err(Error::InvalidTrashInfo(
+                    info.to_string_lossy().to_string(),
+                )));
+            }
+        }
+        let mut errors = self.register(&items);
+        errors.extend(self.commit().err());
+        Results { items, errors }
+    }
+
+    /// Move everything from the trash of the shell implementation in
+    /// `legacy` into this one, batch by batch, keeping aliases that are
+    /// still free. Files that its history does not mention are adopted
+    /// as a last batch under `recovered/`, as by `fsck`, with the name and
+    /// time that their `meta` gives. What cannot be moved stays recorded
+    /// in the old history.
+    pub fn import_legacy(&mut self, legacy: &Path) -> Results<Entry> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return Results::failed(err),
+        };
+        let source = Root::new(legacy.to_path_buf());
+        if let Err(err) = self.foreign(&source) {
+            return Results::failed(err);
+        }
+        // The shell implementation only checks that the lock exists
+        let lock = source.root().join("lock");
+        if lock.exists() {
+            return Results::failed(Error::Locked(lock.to_string_lossy().to_string(), None));
+        }
+        let fs = FsStorage;
+        let mut batches = match fs
+            .read_history(&source)
+            .and_then(|history| crate::history::parse(&history, &source))
+        {
+            Ok(blocks) => blocks,
+            Err(err) => return Results::failed(err),
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let recovered = self.cfg.root().join("recovered");
+        let mut orphans = fs
+            .aliases(&source)
+            .into_iter()
+            .filter(|alias| !batches.iter().flatten().any(|e| e.alias == *alias))
+            .filter(|alias| fs.intact(&source, alias))
+            .map(|alias| {
+                let meta = crate::legacy::Meta::read(&source, &alias);
+                Entry {
+                    name: match &meta {
+                        Some(meta) => recovered.join(&meta.basename),
+                        None => recovered.join(&alias),
+                    },
+                    timestamp: meta.and_then(|meta| meta.removed).unwrap_or(now),
+                    alias,
+                    root: source.clone(),
+                    batch: None,
+                }
+            })
+            .collect::<Vec<_>>();
+        orphans.sort_by_key(|e| e.timestamp);
+        let recorded = batches.len();
+        batches.push(orphans);
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        let mut left = Vec::new();
+        for (num, batch) in batches.iter().enumerate() {
+            let moved = batch
+                .iter()
+                .map(|entry| {
+                    let dir = source.registry().join(&entry.alias);
+                    let alias =
+                        Some(entry.alias.as_str()).filter(|a| crate::legacy::valid_alias(a));
+                    self.take_over(entry, dir.join("file"), dir, alias)
+                })
+                .collect::<Vec<_>>();
+            errors.extend(self.register(&moved));
+            if num < recorded {
+                left.push(
+                    batch
+                        .iter()
+                        .zip(&moved)
+                        .filter(|(entry, res)| res.is_err() && fs.contains(&source, &entry.alias))
+                        .map(|(entry, _)| entry)
+                        .collect::<Vec<_>>(),
+                );
+            }
+            items.extend(moved);
+        }
+        if self.sandbox {
+            self.say(format_args!(
+                "Clean history '{}'",
+                source.history().display()
+            ));
+        } else if let Err(err) = crate::legacy::write_history(&source, &left) {
+            errors.push(err);
+        }
+        errors.extend(self.commit().err());
+        Results { items, errors }
+    }
+
+    /// Trashes can only be imported from outside.
+    fn foreign(&self, source: &Root) -> Result<(), Error> {
+        if self.cfg.roots().any(|root| root == source) {
+            let trash = source.root().to_string_lossy().to_string();
+            return Err(Error::ImportIntoItself(trash));
+        }
+        Ok(())
+    }
+
+    /// Move `file`, which another trash kept for `found`, into this one
+    /// under `alias` if it is free. `leftover` is what remains of it in
+    /// the other trash, deleted once the file is safe.
+    fn take_over(
+        &mut self,
+        found: &Entry,
+        file: PathBuf,
+        leftover: PathBuf,
+        alias: Option<&str>,
+    ) -> Result<Entry, Error> {
+        if !FsStorage.exists(&file) {
+            return Err(Error::FileDoesNotExist(file.to_string_lossy().to_string()));
+        }
+        let root = self.root_for(&file);
+        let alias = match alias {
+            Some(alias) if !self.storage.contains(&root, alias) => alias.to_string(),
+            _ => generate_random_dirname(),
+        };
+        let entry = Entry {
+            name: found.name.clone(),
+            alias,
+            timestamp: found.timestamp,
+            root,
+            batch: None,
+        };
+        if self.sandbox {
+            let moved = Entry {
+                name: file,
+                ..entry.clone()
+            };
+            self.plan(&Op::Remove, &moved);
+            self.say(format_args!("Delete '{}'", leftover.display()));
+            return Ok(entry);
+        }
+        self.intend(Op::Remove, &entry)?;
+        self.storage.import(
+            &entry.root,
+            &entry.alias,
+            &file,
+            &entry.name,
+            entry.timestamp,
+        )?;
+        // The entry is safe either way
+        if let Err(err) = FsStorage.discard(&leftover) {
+            eprintln!("{}", err);
+        }
+        Ok(entry)
+    }
+
+    /// Evict the oldest batches until `paths` fit within `quota` along
+    /// with what is already in the trash. Sizes of stored entries are
+    /// recorded at removal so that only `paths` need to be walked.
+    fn make_room(&mut self, paths: &[PathBuf], quota: u64) -> Result<Results<Entry>, Error> {
+        let existing = paths
+            .iter()
+            .filter(|path| self.storage.exists(path))
+            .collect::<Vec<_>>();
+        // Removals that are bound to fail do not need room
+        if existing.is_empty() {
+            return Ok(Results::default());
+        }
+        let incoming = existing
+            .into_iter()
+            .map(|path| self.storage.size_of(path))
+            .sum::<u64>();
+        if incoming > quota {
+            return Err(Error::LargerThanQuota(incoming, quota));
+        }
+        let entries = self.entries()?;
+        let size = |e: &Entry| self.storage.stored_size(&e.root, &e.alias);
+        let mut used = entries.iter().map(|(_, e)| size(e)).sum::<u64>();
+        if used + incoming <= quota {
+            return Ok(Results::default());
+        }
+        if self.cfg.strict_quota() {
+            return Err(Error::QuotaExceeded(used + incoming, quota));
+        }
+        let mut victims = Vec::new();
+        for batch in entries.batches().rev() {
+            if used + incoming <= quota {
+                break;
+            }
+            for entry in batch.iter().rev() {
+                used -= size(entry);
+                victims.push(entry.clone());
+            }
+        }
+        Ok(self.evict(victims))
+    }
+
+    fn remove_one(&mut self, path: &Path, timestamp: u64) -> Result<Entry, Error> {
+        if !self.storage.exists(path) {
+            return Err(Error::FileDoesNotExist(path.to_string_lossy().to_string()));
+        }
+        let root = self.root_for(path);
+        let alias = self
+            .storage
+            .alias_for(&root, path)
+            .unwrap_or_else(generate_random_dirname);
+        let entry = Entry {
+            name: path.to_path_buf(),
+            alias,
+            timestamp,
+            root,
+            batch: None,
+        };
+        if self.sandbox {
+            self.plan(&Op::Remove, &entry);
+        } else {
+            self.intend(Op::Remove, &entry)?;
+            self.storage
+                .store(&entry.root, &entry.alias, path, timestamp)?;
+        }
+        Ok(entry)
+    }
+
+    /// `batch` as recorded by the current operation.
+    fn stamp(&self, batch: &[&Entry]) -> Vec<Entry> {
+        batch
+            .iter()
+            .map(|entry| Entry {
+                batch: Some(self.batch),
+                ..(*entry).clone()
+            })
+            .collect()
+    }
+
+    fn append_batch(&mut self, root: &Root, batch: &[&Entry]) -> Result<(), Error> {
+        let batch = self.stamp(batch);
+        let batch = batch.iter().collect::<Vec<_>>();
+        if self.sandbox {
+            for entry in batch {
+                self.say(format_args!(
+                    "Save '{}' into '{}'",
+                    entry.record(),
+                    root.history().display()
+                ));
+            }
+            return Ok(());
+        }
+        self.storage.append_batch(root, &batch)
+    }
+
+    /// Everything currently in the trash, newest first.
+    pub fn entries(&self) -> Result<Entries, Error> {
+        Entries::load(&self.storage, &self.cfg)
+    }
+
+    /// Structured data recorded at removal, unavailable for entries
+    /// removed by older versions.
+    pub fn meta(&self, entry: &Entry) -> Option<Meta> {
+        Meta::parse(&self.storage.meta(&entry.root, &entry.alias)?)
+    }
+
+    /// Usage of the trash by the selected entries.
+    pub fn stats(&self, entries: &Entries, selection: &Selection) -> Stats {
+        Stats::compute(&self.storage, entries, selection)
+    }
+
+    /// Human-readable description of the file as it was when removed.
+    pub fn info(&self, entry: &Entry) -> Result<String, Error> {
+        let text = self
+            .storage
+            .meta(&entry.root, &entry.alias)
+            .ok_or_else(|| {
+                let file = entry.root.registry().join(&entry.alias).join("meta");
+                Error::FileDoesNotExist(file.to_string_lossy().to_string())
+            })?;
+        // Data recorded by older versions is already formatted
+        Ok(match Meta::parse(&text) {
+            Some(meta) => meta.to_string(),
+            None => text,
+        })
+    }
+
+    /// Put the selected entries back where they were removed from.
+    /// Each item is where the file ended up.
+    pub fn restore(&mut self, selection: &Selection, options: RestoreOptions) -> Results<PathBuf> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return Results::failed(err),
+        };
+        let items = selection
+            .iter()
+            .map(|(_, entry)| {
+                let dest = self.destination(entry, &options, selection.len())?;
+                self.restore_one(entry, dest, options.overwrite)
+            })
+            .collect();
+        let mut errors = self.clean_history();
+        errors.extend(self.commit().err());
+        Results { items, errors }
+    }
+
+    /// Where `entry` goes when `count` entries are restored with `options`,
+    /// unless something is already there.
+    pub fn destination(
+        &self,
+        entry: &Entry,
+        options: &RestoreOptions,
+        count: usize,
+    ) -> Result<PathBuf, Error> {
+        let to = match &options.to {
+            Some(to) => absolute(to)?,
+            None => return Ok(entry.name.clone()),
+        };
+        if options.parents {
+            let relative = entry.name.strip_prefix("/").unwrap_or(&entry.name);
+            return Ok(to.join(relative));
+        }
+        if count == 1 && !self.storage.is_dir(&to) {
+            return Ok(to);
+        }
+        Ok(match entry.name.file_name() {
+            Some(name) => to.join(name),
+            None => to,
+        })
+    }
+
+    /// Move the file back to `dest`, as prepared by `make_way`.
+    /// The stored data is only deleted once the file is back in place.
+    fn restore_one(
+        &mut self,
+        entry: &Entry,
+        dest: PathBuf,
+        overwrite: bool,
+    ) -> Result<PathBuf, Error> {
+        let dest = self.make_way(dest, overwrite)?;
+        if self.sandbox {
+            self.plan(&Op::Restore(dest.clone()), entry);
+            return Ok(dest);
+        }
+        self.intend(Op::Restore(dest.clone()), entry)?;
+        self.storage.retrieve(&entry.root, &entry.alias, &dest)?;
+        self.storage.remove(&entry.root, &entry.alias)?;
+        Ok(dest)
+    }
+
+    /// Where a file that should go to `dest` can be written, recreating
+    /// its parent directories if needed. If the destination already exists
+    /// the file is renamed to `dest.0`, `dest.1`, ... unless `overwrite` is
+    /// set, in which case what is there is deleted.
+    fn make_way(&mut self, dest: PathBuf, overwrite: bool) -> Result<PathBuf, Error> {
+        let sandbox = self.sandbox;
+        let wanted = dest.clone();
+        let mut dest = dest;
+        if let Some(parent) = dest.parent() {
+            if sandbox {
+                self.say(format_args!("Create directory '{}'", parent.display()));
+            } else {
+                self.storage.create_dir_all(parent)?;
+            }
+        }
+        if self.storage.exists(&dest) {
+            if overwrite {
+                if sandbox {
+                    self.say(format_args!("Delete '{}'", dest.display()));
+                } else {
+                    self.storage.discard(&dest)?;
+                }
+            } else {
+                let mut id = 0;
+                dest = loop {
+                    let mut candidate = wanted.clone().into_os_string();
+                    candidate.push(format!(".{}", id));
+                    let candidate = PathBuf::from(candidate);
+                    if !self.storage.exists(&candidate) {
+                        break candidate;
+                    }
+                    id += 1;
+                };
+            }
+        }
+        Ok(dest)
+    }
+
+    /// Write the selected entries to `out` one after the other, or the file
+    /// at `inside` each of them. Only regular files can be written this way,
+    /// and the trash is left as it is.
+    pub fn cat<W: Write>(
+        &self,
+        selection: &Selection,
+        inside: Option<&Path>,
+        out: &mut W,
+    ) -> Results<()> {
+        let mut items = Vec::new();
+        for (_, entry) in selection {
+            // As the user knows it rather than as it is stored
+            let name = match inside {
+                Some(inside) => entry.name.join(inside),
+                None => entry.name.clone(),
+            };
+            let name = name.to_string_lossy().to_string();
+            let read = inner(entry, inside)
+                .and_then(|inner| self.storage.read_stored(&entry.root, &entry.alias, &inner));
+            let mut file = match read {
+                Ok(file) => file,
+                Err(Error::NotRegularFile(_)) => {
+                    items.push(Err(Error::NotRegularFile(name)));
+                    continue;
+                }
+                Err(err) => {
+                    items.push(Err(err));
+                    continue;
+                }
+            };
+            match std::io::copy(&mut file, out).and_then(|_| out.flush()) {
+                Ok(_) => items.push(Ok(())),
+                // Whoever reads the output has seen enough, e.g. `head`
+                Err(err) if err.kind() == ErrorKind::BrokenPipe => break,
+                Err(_) => items.push(Err(Error::CouldNotCopy(name, String::from("stdout")))),
+            }
+        }
+        Results {
+            items,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Copy the selected entries, or the file at `inside` each of them, out
+    /// of the trash without restoring them. They go where `restore` would
+    /// put them with the same `options`, except that without `to` they are
+    /// copied into the current directory. The trash is left as it is.
+    /// Each item is where the copy ended up.
+    pub fn extract(
+        &mut self,
+        selection: &Selection,
+        inside: Option<&Path>,
+        options: &RestoreOptions,
+    ) -> Results<PathBuf> {
+        let to = match &options.to {
+            Some(to) => absolute(to),
+            None => current_dir(),
+        };
+        let to = match to {
+            Ok(to) => to,
+            Err(err) => return Results::failed(err),
+        };
+        let into = selection.len() != 1 || self.storage.is_dir(&to);
+        let items = selection
+            .iter()
+            .map(|(_, entry)| {
+                let inner = inner(entry, inside)?;
+                let src = self.storage.lookup(&entry.root, &entry.alias, &inner)?;
+                let wanted = if options.parents {
+                    let relative = entry.name.strip_prefix("/").unwrap_or(&entry.name);
+                    within(to.join(relative), &inner)
+                } else {
+                    // Only the file at `inside` is copied, under its own name
+                    match inner.file_name().or_else(|| entry.name.file_name()) {
+                        Some(name) if into => to.join(name),
+                        _ => to.clone(),
+                    }
+                };
+                let copy = self.make_way(wanted, options.overwrite)?;
+                if self.sandbox {
+                    self.say(format_args!(
+                        "Copy '{}' to '{}'",
+                        src.display(),
+                        copy.display()
+                    ));
+                } else {
+                    self.storage
+                        .copy_stored(&entry.root, &entry.alias, &inner, &copy)?;
+                }
+                Ok(copy)
+            })
+            .collect();
+        Results {
+            items,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Permanently delete the selected entries.
+    pub fn purge(&mut self, selection: &Selection) -> Results<()> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return Results::failed(err),
+        };
+        let items = selection
+            .iter()
+            .map(|(_, entry)| self.purge_one(entry))
+            .collect();
+        let mut errors = self.clean_history();
+        errors.extend(self.commit().err());
+        Results { items, errors }
+    }
+
+    fn purge_one(&mut self, entry: &Entry) -> Result<(), Error> {
+        if self.sandbox {
+            self.plan(&Op::Purge, entry);
+            return Ok(());
+        }
+        self.intend(Op::Purge, entry)?;
+        self.storage.remove(&entry.root, &entry.alias)
+    }
+
+    /// Permanently delete the entries that were removed more than `max_age`
+    /// seconds ago.
+    pub fn expire(&mut self, max_age: u64) -> Results<Entry> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return Results::failed(err),
+        };
+        let entries = match self.entries() {
+            Ok(entries) => entries,
+            Err(err) => return Results::failed(err),
+        };
+        let mut selection = Selection::new();
+        crate::select::Time::new(max_age, u64::MAX).select(&entries, &mut selection);
+        let victims = selection.iter().map(|(_, e)| (*e).clone()).collect();
+        self.evict(victims)
+    }
+
+    /// Purge entries on behalf of the retention policy. Each of them is
+    /// appended to the `expired` log of the main root, along with the time
+    /// at which it was purged.
+    fn evict(&mut self, victims: Vec<Entry>) -> Results<Entry> {
+        if victims.is_empty() {
+            return Results::default();
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut log = String::new();
+        let items = victims
+            .into_iter()
+            .map(|entry| {
+                self.purge_one(&entry)?;
+                log.push_str(&format!("{} {}\n", now, entry.record()));
+                Ok(entry)
+            })
+            .collect::<Vec<_>>();
+        let mut errors = self.clean_history();
+        let path = self.cfg.expired().to_path_buf();
+        if self.sandbox {
+            for line in log.lines() {
+                self.say(format_args!("Save '{}' into '{}'", line, path.display()));
+            }
+        } else if let Err(err) = self.storage.append_log(&path, log.as_bytes()) {
+            errors.push(err);
+        }
+        errors.extend(self.commit().err());
+        Results { items, errors }
+    }
+
+    /// Check that the history of every root agrees with what is stored,
+    /// and fix it if `repair` is set. Each item is a problem that was found.
+    pub fn fsck(&mut self, repair: bool) -> Results<Issue> {
+        let _lock = match self.lock() {
+            Ok(lock) => lock,
+            Err(err) => return Results::failed(err),
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut results = Results::default();
+        let roots = self.cfg.roots().cloned().collect::<Vec<_>>();
+        for root in &roots {
+            let check = match Check::run(&self.storage, root, now) {
+                Ok(check) => check,
+                Err(err) => {
+                    results.errors.push(err);
+                    continue;
+                }
+            };
+            results.items.extend(check.issues.iter().cloned().map(Ok));
+            if repair && !check.issues.is_empty() {
+                if let Err(err) = self.repair(root, &check) {
+                    results.errors.push(err);
+                }
+            }
+        }
+        results
+    }
+
+    fn repair(&mut self, root: &Root, check: &Check) -> Result<(), Error> {
+        if self.sandbox {
+            for alias in &check.discarded {
+                self.say(format_args!(
+                    "Delete '{}'",
+                    root.registry().join(alias).display()
+                ));
+            }
+            self.say(format_args!("Clean history '{}'", root.history().display()));
+            for entry in &check.adopted {
+                self.say(format_args!(
+                    "Save '{}' into '{}'",
+                    entry.record(),
+                    root.history().display()
+                ));
+            }
+            return Ok(());
+        }
+        for alias in &check.discarded {
+            self.storage.remove(root, alias)?;
+        }
+        self.storage.write_history(root, &check.history())
+    }
+
+    /// Rewrite the history of every root, dropping the entries that are no
+    /// longer stored. Batches are kept separate, and batches that become
+    /// empty disappear entirely. The histories are read again since the
+    /// selection may only cover part of them.
+    fn clean_history(&mut self) -> Vec<Error> {
+        let entries = match self.entries() {
+            Ok(entries) => entries,
+            Err(err) => return vec![err],
+        };
+        let mut errors = Vec::new();
+        for root in self.cfg.roots() {
+            if self.sandbox {
+                self.say(format_args!("Clean history '{}'", root.history().display()));
+                continue;
+            }
+            let storage = &self.storage;
+            let kept = entries
+                .batches()
+                .rev()
+                .map(|batch| {
+                    batch
+                        .iter()
+                        .rev()
+                        .filter(|e| e.root == *root && storage.contains(root, &e.alias))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            if let Err(err) = self.storage.write_history(root, &kept) {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+}
+
+const ALIAS_LENGTH: usize = 25;
+
+/// The directory that relative paths start from.
+fn current_dir() -> Result<PathBuf, Error> {
+    std::env::current_dir().map_err(|_| Error::NoCurrentDir)
+}
+
+/// `path` taken from the current directory if it is relative.
+fn absolute(path: &Path) -> Result<PathBuf, Error> {
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    Ok(current_dir()?.join(path))
+}
+
+/// `inside` relative to the file stored for `entry`. It can also be given
+/// as it was before removal, and cannot point outside of the entry.
+fn inner(entry: &Entry, inside: Option<&Path>) -> Result<PathBuf, Error> {
+    let inside = match inside {
+        Some(inside) => inside,
+        None => return Ok(PathBuf::new()),
+    };
+    let relative = inside.strip_prefix(&entry.name).unwrap_or(inside);
+    let mut inner = PathBuf::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => inner.push(part),
+            Component::CurDir => (),
+            _ => return Err(Error::OutsideEntry(inside.to_string_lossy().to_string())),
+        }
+    }
+    Ok(inner)
+}
+
+fn generate_random_dirname() -> String {
+    use rand::{distributions::Alphanumeric, Rng};
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ALIAS_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::select::{Block, Index, Pattern, Select, Time};
+    use crate::storage::MemoryStorage;
+    use crate::testing::TempDir;
+
+    fn trash(files: &[&str]) -> Trash<MemoryStorage> {
+        let mut storage = MemoryStorage::new();
+        for f in files {
+            storage.insert(*f, f.as_bytes());
+        }
+        Trash::with_storage(Config::detached(PathBuf::from("/trash")), storage)
+    }
+
+    fn select<'i, S: Select>(entries: &'i Entries, sel: S) -> Selection<'i> {
+        let mut selection = Selection::new();
+        sel.select(entries, &mut selection);
+        selection
+    }
+
+    fn file<'t>(trash: &'t Trash<MemoryStorage>, path: &str) -> Option<&'t [u8]> {
+        trash.storage().file(Path::new(path))
+    }
+
+    #[test]
+    fn remove_and_restore() {
+        let mut trash = trash(&["/home/a", "/home/dir/b", "/home/dir/c"]);
+        let removed = trash
+            .remove(["/home/a", "/home/dir", "/home/missing"])
+            .removed;
+        assert!(removed.errors.is_empty());
+        assert!(removed.items[0].is_ok() && removed.items[1].is_ok());
+        assert!(matches!(removed.items[2], Err(Error::FileDoesNotExist(_))));
+        assert_eq!(file(&trash, "/home/a"), None);
+        assert_eq!(trash.storage().log(Path::new("/trash/journal")), None);
+        let entries = trash.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.block_of(2), 1);
+        trash.storage.insert("/home/a", b"conflict");
+        let restored = trash.restore(
+            &select(&entries, Index::new(1, 2)),
+            RestoreOptions::default(),
+        );
+        assert!(restored.errors.is_empty());
+        let dests = restored
+            .items
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dests,
+            vec![PathBuf::from("/home/dir"), PathBuf::from("/home/a.0")]
+        );
+        assert_eq!(file(&trash, "/home/a.0"), Some(&b"/home/a"[..]));
+        assert_eq!(file(&trash, "/home/a"), Some(&b"conflict"[..]));
+        assert_eq!(file(&trash, "/home/dir/c"), Some(&b"/home/dir/c"[..]));
+        assert!(trash.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_overwrite() {
+        let mut trash = trash(&["/home/a"]);
+        trash.remove(["/home/a"]);
+        trash.storage.insert("/home/a", b"conflict");
+        let entries = trash.entries().unwrap();
+        let options = RestoreOptions {
+            overwrite: true,
+            ..RestoreOptions::default()
+        };
+        let restored = trash.restore(&select(&entries, Index::new(1, 1)), options);
+        assert_eq!(restored.items[0].as_ref().unwrap(), Path::new("/home/a"));
+        assert_eq!(file(&trash, "/home/a"), Some(&b"/home/a"[..]));
+        assert_eq!(file(&trash, "/home/a.0"), None);
+    }
+
+    #[test]
+    fn restore_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = TempDir::new("non-utf8");
+        let name = dir.join(OsStr::from_bytes(b"caf\xe9|\n"));
+        std::fs::write(&name, "contents").unwrap();
+        let mut trash = Trash::new(Config::new(dir.join("trash")).unwrap());
+        assert!(trash.remove([&name]).removed.errors.is_empty());
+        assert!(!name.exists());
+        // Read back from the history by another process
+        let mut trash = Trash::new(Config::new(dir.join("trash")).unwrap());
+        let entries = trash.entries().unwrap();
+        assert_eq!(entries.get(1).unwrap().name, name);
+        let restored = trash.restore(
+            &select(&entries, Index::new(1, 1)),
+            RestoreOptions::default(),
+        );
+        assert!(restored.errors.is_empty());
+        assert_eq!(restored.items[0].as_ref().unwrap(), &name);
+        assert_eq!(std::fs::read_to_string(&name).unwrap(), "contents");
+        assert!(trash.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_elsewhere() {
+        let to = |path: &str, parents: bool| RestoreOptions {
+            to: Some(PathBuf::from(path)),
+            parents,
+            ..RestoreOptions::default()
+        };
+        let mut trash = trash(&["/home/a", "/home/dir/b", "/home/c", "/home/d"]);
+        trash.remove(["/home/a", "/home/dir"]);
+        trash.storage.insert("/scratch/a", b"conflict");
+        let entries = trash.entries().unwrap();
+        let both = select(&entries, Index::new(1, 2));
+        let restored = trash.restore(&both, to("/scratch", false));
+        assert!(restored.errors.is_empty());
+        assert_eq!(file(&trash, "/scratch/a.0"), Some(&b"/home/a"[..]));
+        assert_eq!(file(&trash, "/scratch/dir/b"), Some(&b"/home/dir/b"[..]));
+        assert_eq!(file(&trash, "/home/a"), None);
+        // A single entry goes into a directory, or becomes the path
+        trash.remove(["/home/c"]);
+        trash.remove(["/home/d"]);
+        let entries = trash.entries().unwrap();
+        trash.restore(&select(&entries, Index::new(1, 1)), to("/scratch", false));
+        trash.restore(&select(&entries, Index::new(2, 2)), to("/out/e", false));
+        assert_eq!(file(&trash, "/scratch/d"), Some(&b"/home/d"[..]));
+        assert_eq!(file(&trash, "/out/e"), Some(&b"/home/c"[..]));
+        trash.remove(["/scratch/d"]);
+        let entries = trash.entries().unwrap();
+        let options = to("/tree", true);
+        let entry = entries.get(1).unwrap();
+        assert_eq!(
+            trash.destination(entry, &options, 1).unwrap(),
+            Path::new("/tree/scratch/d")
+        );
+        trash.restore(&select(&entries, Index::new(1, 1)), options);
+        assert_eq!(file(&trash, "/tree/scratch/d"), Some(&b"/home/d"[..]));
+    }
+
+    #[test]
+    fn extract() {
+        let to = |path: &str, parents: bool| RestoreOptions {
+            to: Some(PathBuf::from(path)),
+            parents,
+            ..RestoreOptions::default()
+        };
+        let mut trash = trash(&["/home/a", "/home/dir/b", "/home/dir/sub/c"]);
+        trash.remove(["/home/a", "/home/dir"]);
+        let entries = trash.entries().unwrap();
+        let both = select(&entries, Index::new(1, 2));
+        let mut out = Vec::new();
+        let printed = trash.cat(&both, None, &mut out);
+        assert_eq!(out, b"/home/a");
+        assert!(matches!(printed.items[0], Err(Error::NotRegularFile(_))));
+        let dir = select(&entries, Index::new(1, 1));
+        out.clear();
+        trash.cat(&dir, Some(Path::new("/home/dir/sub/c")), &mut out);
+        assert_eq!(out, b"/home/dir/sub/c");
+        let outside = trash.cat(&dir, Some(Path::new("../a")), &mut out);
+        assert!(matches!(outside.items[0], Err(Error::OutsideEntry(_))));
+        // Copies are made next to existing files, and the trash stays as is
+        trash.storage.insert("/out/a", b"conflict");
+        let extracted = trash.extract(&both, None, &to("/out", false));
+        assert!(extracted.items.iter().all(Result::is_ok));
+        assert_eq!(file(&trash, "/out/a.0"), Some(&b"/home/a"[..]));
+        assert_eq!(
+            file(&trash, "/out/dir/sub/c"),
+            Some(&b"/home/dir/sub/c"[..])
+        );
+        trash.extract(&dir, Some(Path::new("sub")), &to("/copy", false));
+        assert_eq!(file(&trash, "/copy/c"), Some(&b"/home/dir/sub/c"[..]));
+        trash.extract(&dir, Some(Path::new("sub/c")), &to("/tree", true));
+        assert_eq!(
+            file(&trash, "/tree/home/dir/sub/c"),
+            Some(&b"/home/dir/sub/c"[..])
+        );
+        let missing = trash.extract(&dir, Some(Path::new("d")), &to("/copy", false));
+        assert!(matches!(missing.items[0], Err(Error::FileDoesNotExist(_))));
+        assert_eq!(trash.entries().unwrap().len(), 2);
+        assert_eq!(file(&trash, "/home/a"), None);
+    }
+
+    #[test]
+    fn purge() {
+        let mut trash = trash(&["/x", "/y", "/z"]);
+        trash.remove(["/x", "/y"]);
+        trash.remove(["/z"]);
+        let entries = trash.entries().unwrap();
+        let purged = trash.purge(&select(
+            &entries,
+            Pattern::new(regex::Regex::new("y").unwrap()),
+        ));
+        assert!(purged.items.iter().all(Result::is_ok));
+        let left = trash.entries().unwrap();
+        let names = left.iter().map(|(_, e)| e.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names, vec![PathBuf::from("/z"), PathBuf::from("/x")]);
+        // Batches are preserved
+        assert_eq!(left.block_of(2), 2);
+        let purged = trash.purge(&select(&left, Block::new(1, 2)));
+        assert_eq!(purged.items.len(), 2);
+        assert!(trash.entries().unwrap().is_empty());
+        assert_eq!(file(&trash, "/x"), None);
+    }
+
+    #[test]
+    fn expire() {
+        let mut trash = trash(&["/old", "/new"]);
+        trash.remove(["/old"]);
+        // Pretend it was removed long ago
+        let mut old = trash.entries().unwrap().get(1).unwrap().clone();
+        old.timestamp = 1000;
+        let root = old.root.clone();
+        trash.storage.write_history(&root, &[vec![&old]]).unwrap();
+        trash.remove(["/new"]);
+        assert_eq!(trash.entries().unwrap().len(), 2);
+        let expired = trash.expire(60 * 60 * 24);
+        assert!(expired.errors.is_empty());
+        assert_eq!(expired.items.len(), 1);
+        assert_eq!(
+            expired.items[0].as_ref().unwrap().name,
+            PathBuf::from("/old")
+        );
+        let left = trash.entries().unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left.get(1).unwrap().name, PathBuf::from("/new"));
+        assert!(!trash.storage().contains(&root, &old.alias));
+        let log = trash.storage().log(Path::new("/trash/expired")).unwrap();
+        let log = String::from_utf8(log.to_vec()).unwrap();
+        assert!(log.ends_with(&format!(" {}\n", old.record())));
+        // Nothing left to expire
+        assert!(trash.expire(60 * 60 * 24).items.is_empty());
+    }
+
+    #[test]
+    fn quota() {
+        let mut storage = MemoryStorage::new();
+        for (name, size) in &[("/a", 4), ("/b", 3), ("/c", 2), ("/d", 5), ("/e", 11)] {
+            storage.insert(*name, &vec![0; *size]);
+        }
+        let cfg = Config::detached(PathBuf::from("/trash")).with_quota(Some(10), false);
+        let mut trash = Trash::with_storage(cfg, storage);
+        trash.remove(["/a", "/b"]);
+        trash.remove(["/c"]);
+        // The oldest batch goes as a whole, even if part of it would do
+        let removal = trash.remove(["/d"]);
+        let evicted = removal
+            .evicted
+            .items
+            .iter()
+            .map(|e| e.as_ref().unwrap().name.clone());
+        assert_eq!(
+            evicted.collect::<Vec<_>>(),
+            vec![PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+        assert!(removal.removed.items[0].is_ok());
+        let names = |trash: &Trash<MemoryStorage>| {
+            let entries = trash.entries().unwrap();
+            entries
+                .iter()
+                .map(|(_, e)| e.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&trash),
+            vec![PathBuf::from("/d"), PathBuf::from("/c")]
+        );
+        assert!(trash.storage().log(Path::new("/trash/expired")).is_some());
+        // Nothing can make room for this one
+        let removal = trash.remove(["/e"]);
+        assert!(matches!(
+            removal.removed.errors[..],
+            [Error::LargerThanQuota(11, 10)]
+        ));
+        assert!(removal.evicted.items.is_empty());
+        assert!(file(&trash, "/e").is_some());
+        let mut strict = Trash {
+            cfg: Config::detached(PathBuf::from("/trash")).with_quota(Some(10), true),
+            ..trash
+        };
+        strict.storage.insert("/f", &[0; 4]);
+        let removal = strict.remove(["/f"]);
+        assert!(matches!(
+            removal.removed.errors[..],
+            [Error::QuotaExceeded(11, 10)]
+        ));
+        assert_eq!(names(&strict).len(), 2);
+        assert!(file(&strict, "/f").is_some());
+    }
+
+    #[test]
+    fn fsck_repair() {
+        let mut trash = trash(&["/a", "/b"]);
+        trash.remove(["/a", "/b"]);
+        let entries = trash.entries().unwrap();
+        let a = entries.get(2).unwrap().clone();
+        let root = a.root.clone();
+        let history = format!("#rem-history 2\n\n{}\nx|/x|5\nbroken\n", a.record());
+        trash.storage.set_history(&root, history.as_bytes());
+        let found = trash.fsck(false);
+        assert_eq!(found.items.len(), 3);
+        assert_eq!(
+            trash.entries().unwrap_err().parts().unwrap().0,
+            "Corrupted (missing) data"
+        );
+        let repaired = trash.fsck(true);
+        assert!(repaired.errors.is_empty());
+        assert_eq!(repaired.items.len(), 3);
+        let entries = trash.entries().unwrap();
+        let names = entries
+            .iter()
+            .map(|(_, e)| e.name.clone())
+            .collect::<Vec<_>>();
+        // Nothing is known about the orphan in memory
+        let recovered = Path::new("/trash/recovered").join(&entries.get(1).unwrap().alias);
+        assert_eq!(names, vec![recovered, PathBuf::from("/a")]);
+        assert!(trash.fsck(false).items.is_empty());
+    }
+
+    #[test]
+    fn recover() {
+        use crate::journal::{Intent, Op, HEADER};
+        let mut trash = trash(&["/kept", "/moved", "/restored", "/purged", "/untouched"]);
+        trash.remove(["/kept", "/restored", "/purged"]);
+        let entries = trash.entries().unwrap();
+        let entry = |name: &str| {
+            let found = entries.iter().find(|(_, e)| e.name == Path::new(name));
+            found.unwrap().1.clone()
+        };
+        let root = entry("/kept").root;
+        // Interrupted by a crash after these
+        let moved = Entry {
+            name: PathBuf::from("/moved"),
+            alias: String::from("moved"),
+            ..entry("/kept")
+        };
+        let untouched = Entry {
+            name: PathBuf::from("/untouched"),
+            alias: String::from("untouched"),
+            ..entry("/kept")
+        };
+        trash
+            .storage
+            .store(&root, "moved", Path::new("/moved"), 0)
+            .unwrap();
+        let restored = entry("/restored");
+        trash
+            .storage
+            .retrieve(&root, &restored.alias, Path::new("/restored"))
+            .unwrap();
+        let intents = vec![
+            Intent::new(Op::Remove, &moved),
+            Intent::new(Op::Remove, &untouched),
+            Intent::new(Op::Restore(PathBuf::from("/restored")), &restored),
+            Intent::new(Op::Purge, &entry("/purged")),
+        ];
+        let mut journal = format!("{}\n", HEADER);
+        for intent in intents {
+            journal.push_str(&intent.serialize());
+        }
+        trash
+            .storage
+            .append_log(Path::new("/trash/journal"), journal.as_bytes())
+            .unwrap();
+        trash.recover().unwrap();
+        let left = trash.entries().unwrap();
+        let names = left.iter().map(|(_, e)| e.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names, vec![PathBuf::from("/moved"), PathBuf::from("/kept")]);
+        assert!(!trash.storage().contains(&root, &restored.alias));
+        assert!(!trash.storage().contains(&root, &entry("/purged").alias));
+        assert_eq!(file(&trash, "/restored"), Some(&b"/restored"[..]));
+        assert_eq!(file(&trash, "/untouched"), Some(&b"/untouched"[..]));
+        assert_eq!(trash.storage().log(Path::new("/trash/journal")), None);
+    }
+
+    #[test]
+    fn recover_xdg() {
+        use crate::journal::{Intent, Op, HEADER};
+        let dir = TempDir::new("recover");
+        let cfg = Config::new(dir.join("rem"))
+            .unwrap()
+            .with_xdg(Some(dir.join("Trash")));
+        let purged = dir.join("purged");
+        std::fs::write(&purged, "purged").unwrap();
+        let mut trash = Trash::with_storage(cfg, XdgStorage);
+        assert!(trash.remove([&purged]).removed.errors.is_empty());
+        let entry = trash.entries().unwrap().get(1).unwrap().clone();
+        // Interrupted before anything was deleted
+        let journal = format!("{}\n{}", HEADER, Intent::new(Op::Purge, &entry).serialize());
+        std::fs::write(trash.config().journal(), journal).unwrap();
+        trash.recover().unwrap();
+        assert!(trash.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sandbox_changes_nothing() {
+        let mut trash = trash(&["/x"]).sandbox(true);
+        let removed = trash.remove(["/x"]).removed;
+        assert!(removed.items[0].is_ok());
+        assert!(file(&trash, "/x").is_some());
+        assert!(trash.entries().unwrap().is_empty());
+        let mut real = self::trash(&["/x"]);
+        real.remove(["/x"]);
+        let entries = real.entries().unwrap();
+        let mut sandboxed = Trash {
+            sandbox: true,
+            ..real
+        };
+        let selection = select(&entries, Time::new(0, u64::MAX));
+        assert_eq!(selection.len(), 1);
+        sandboxed.purge(&selection);
+        sandboxed.restore(&selection, RestoreOptions::default());
+        assert_eq!(sandboxed.entries().unwrap().len(), 1);
+        assert!(file(&sandboxed, "/x").is_none());
+    }
+
+    #[test]
+    fn import_xdg() {
+        let dir = TempDir::new("import");
+        let xdg = dir.join("Trash");
+        std::fs::create_dir_all(xdg.join("files")).unwrap();
+        std::fs::create_dir_all(xdg.join("info")).unwrap();
+        for (name, date) in &[
+            ("new", "2021-07-12T08:00:00"),
+            ("old", "2021-07-11T08:00:00"),
+        ] {
+            std::fs::write(xdg.join("files").join(name), name).unwrap();
+            let info = format!("[Trash Info]\nPath=/home/{}\nDeletionDate={}\n", name, date);
+            std::fs::write(xdg.join("info").join(format!("{}.trashinfo", name)), info).unwrap();
+        }
+        std::fs::write(xdg.join("files/unknown"), "?").unwrap();
+        std::fs::write(xdg.join("info/unknown.trashinfo"), "[Trash Info]\n").unwrap();
+        let mut trash = Trash::new(Config::new(dir.join("rem")).unwrap());
+        let recent = dir.join("recent");
+        std::fs::write(&recent, "recent").unwrap();
+        assert!(trash.remove([&recent]).removed.errors.is_empty());
+        let imported = trash.import_xdg(&xdg);
+        assert!(imported.errors.is_empty());
+        assert_eq!(imported.items.len(), 3);
+        assert!(matches!(imported.items[2], Err(Error::InvalidTrashInfo(_))));
+        let entries = trash.entries().unwrap();
+        let names = entries
+            .iter()
+            .map(|(_, e)| e.name.clone())
+            .collect::<Vec<_>>();
+        // Imported last, whatever the dates of the files
+        assert_eq!(
+            names,
+            vec![
+                PathBuf::from("/home/new"),
+                PathBuf::from("/home/old"),
+                recent
+            ]
+        );
+        assert_eq!(entries.batches().count(), 2);
+        let old = entries.get(2).unwrap();
+        assert_eq!(
+            old.timestamp,
+            crate::xdg::parse_date("2021-07-11T08:00:00").unwrap()
+        );
+        assert_eq!(trash.meta(old).unwrap().name, Path::new("/home/old"));
+        // Removed a day before the other one
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let age = now - entries.get(1).unwrap().timestamp;
+        let older = select(&entries, Time::new(age + 3600, u64::MAX));
+        assert_eq!(older.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![2]);
+        assert!(!xdg.join("files/old").exists() && !xdg.join("info/old.trashinfo").exists());
+        assert!(xdg.join("files/unknown").exists());
+    }
+
+    #[test]
+    fn import_legacy() {
+        let dir = TempDir::new("legacy");
+        let legacy = Root::new(dir.join(".trash"));
+        for alias in &["MTIz", "NDU2", "T3Jw"] {
+            let stored = legacy.registry().join(alias);
+            std::fs::create_dir_all(&stored).unwrap();
+            std::fs::write(stored.join("file"), alias).unwrap();
+        }
+        std::fs::write(
+            legacy.registry().join("T3Jw/meta"),
+            "orphan.txt\n2021-07-11 12:30:00\n\n-rw-r--r-- 1 user user 4 orphan.txt\n",
+        )
+        .unwrap();
+        std::fs::write(
+            legacy.history(),
+            "\nMTIz|/home/a|100\nR29u|/home/gone|100\n\nNDU2|/home/b|200\n",
+        )
+        .unwrap();
+        let cfg = Config::new(dir.join("rem")).unwrap();
+        // Already taken in the current trash
+        std::fs::create_dir(cfg.registry().join("NDU2")).unwrap();
+        let recovered = cfg.root().join("recovered/orphan.txt");
+        let mut trash = Trash::new(cfg);
+        let imported = trash.import_legacy(legacy.root());
+        assert!(imported.errors.is_empty());
+        assert_eq!(imported.items.len(), 4);
+        assert!(matches!(imported.items[1], Err(Error::FileDoesNotExist(_))));
+        let entries = trash.entries().unwrap();
+        assert_eq!(entries.batches().count(), 3);
+        let orphan = entries.get(1).unwrap();
+        assert_eq!(orphan.name, recovered);
+        assert_eq!(
+            orphan.timestamp,
+            crate::xdg::parse_date("2021-07-11T12:30:00").unwrap()
+        );
+        assert_eq!(orphan.alias, "T3Jw");
+        let (b, a) = (entries.get(2).unwrap(), entries.get(3).unwrap());
+        assert_eq!((a.alias.as_str(), a.timestamp), ("MTIz", 100));
+        assert_eq!(b.name, Path::new("/home/b"));
+        assert_ne!(b.alias, "NDU2");
+        assert_eq!(trash.meta(a).unwrap().name, Path::new("/home/a"));
+        assert_eq!(FsStorage.aliases(&legacy), Vec::<String>::new());
+        let history = std::fs::read(legacy.history()).unwrap();
+        assert!(crate::history::parse(&history, &legacy).unwrap().is_empty());
+    }
+}
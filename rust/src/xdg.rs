@@ -0,0 +1,530 @@
+//! Storage in the trash shared with desktop environments, as described by
+//! the FreeDesktop.org Trash specification.
+//!
+//! ```text
+//! $XDG_DATA_HOME/Trash/files/<name>
+//! $XDG_DATA_HOME/Trash/info/<name>.trashinfo
+//! ```
+//!
+//! Each `.trashinfo` records where the file was and when it was removed,
+//! in local time:
+//!
+//! ```text
+//! [Trash Info]
+//! Path=/home/user/some%20file
+//! DeletionDate=2021-07-11T12:30:00
+//! ```
+//!
+//! There is no history: it is rebuilt from these files, and entries removed
+//! within the same second form a batch. Aliases are the names in `files/`,
+//! percent-encoded so that they can be recorded whatever tool trashed them.
+
+use crate::command::Error;
+use crate::config::{Config, Root};
+use crate::journal::Op;
+use crate::lock::Lock;
+use crate::meta::Meta;
+use crate::select::Entry;
+use crate::storage::{FsStorage, Storage};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+const SECTION: &str = "[Trash Info]";
+const EXTENSION: &str = ".trashinfo";
+
+/// `$XDG_DATA_HOME/Trash`, falling back to `~/.local/share/Trash`.
+pub fn home_trash() -> Option<PathBuf> {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+    Some(data.join("Trash"))
+}
+
+/// Escape everything but unreserved characters and `/`, as in URIs.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                res.push(b as char)
+            }
+            b => res.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    res
+}
+
+/// Inverse of `encode`, accepting anything that other tools may have
+/// left unescaped. Fails on malformed escape sequences.
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    let mut res = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            res.push(b);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        res.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    Some(res)
+}
+
+/// Local time as `YYYY-MM-DDTHH:MM:SS`
+pub fn format_date(timestamp: u64) -> String {
+    let time = timestamp as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return String::from("1970-01-01T00:00:00");
+    }
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// Inverse of `format_date`.
+pub fn parse_date(text: &str) -> Option<u64> {
+    let (date, time) = text.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|n| n.parse::<libc::c_int>().ok());
+    // Fractions of a second and time zones are not part of the format,
+    // but are harmless
+    let time = time.get(..8)?;
+    let mut time = time.splitn(3, ':').map(|n| n.parse::<libc::c_int>().ok());
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = date.next()?? - 1900;
+    tm.tm_mon = date.next()?? - 1;
+    tm.tm_mday = date.next()??;
+    tm.tm_hour = time.next()??;
+    tm.tm_min = time.next()??;
+    tm.tm_sec = time.next()??;
+    tm.tm_isdst = -1;
+    let timestamp = unsafe { libc::mktime(&mut tm) };
+    if timestamp < 0 {
+        return None;
+    }
+    Some(timestamp as u64)
+}
+
+/// Contents of a `.trashinfo` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashInfo {
+    pub path: PathBuf,
+    pub deleted: u64,
+}
+
+impl TrashInfo {
+    pub fn serialize(&self) -> String {
+        format!(
+            "{}\nPath={}\nDeletionDate={}\n",
+            SECTION,
+            encode(self.path.as_os_str().as_bytes()),
+            format_date(self.deleted)
+        )
+    }
+
+    /// Relative paths are relative to the directory that holds the trash.
+    pub fn parse(text: &str, trash: &Path) -> Option<Self> {
+        let mut lines = text.lines().map(str::trim).skip_while(|l| *l != SECTION);
+        lines.next()?;
+        let (mut path, mut deleted) = (None, None);
+        for line in lines.take_while(|l| !l.starts_with('[')) {
+            if let Some(value) = line.strip_prefix("Path=") {
+                path = Some(PathBuf::from(OsString::from_vec(decode(value)?)));
+            } else if let Some(value) = line.strip_prefix("DeletionDate=") {
+                deleted = Some(parse_date(value)?);
+            }
+        }
+        let path = path?;
+        Some(Self {
+            path: match trash.parent() {
+                Some(top) if path.is_relative() => top.join(path),
+                _ => path,
+            },
+            deleted: deleted?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct XdgStorage;
+
+impl XdgStorage {
+    fn name(alias: &str) -> OsString {
+        OsString::from_vec(decode(alias).unwrap_or_else(|| alias.as_bytes().to_vec()))
+    }
+
+    /// Locations are derived from the directory only, since entries read
+    /// from the journal do not know what kind of root they belong to.
+    pub fn file(root: &Root, alias: &str) -> PathBuf {
+        root.root().join("files").join(Self::name(alias))
+    }
+
+    pub fn info(root: &Root, alias: &str) -> PathBuf {
+        let mut name = Self::name(alias);
+        name.push(EXTENSION);
+        root.root().join("info").join(name)
+    }
+
+    fn read_info(root: &Root, alias: &str) -> Option<TrashInfo> {
+        let text = std::fs::read_to_string(Self::info(root, alias)).ok()?;
+        TrashInfo::parse(&text, root.root())
+    }
+
+    fn write_info(root: &Root, alias: &str, info: &TrashInfo, new: bool) -> Result<(), Error> {
+        use std::io::Write;
+        let file = Self::info(root, alias);
+        let dir = root.root().join("info");
+        std::fs::create_dir_all(&dir)
+            .map_err(|_| Error::CouldNotCreateDir(dir.to_string_lossy().to_string()))?;
+        // Creating the file is what reserves the name
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .create_new(new)
+            .truncate(true)
+            .open(&file)
+            .and_then(|mut f| f.write_all(info.serialize().as_bytes()))
+            .map_err(|_| Error::FailedToWrite(file.to_string_lossy().to_string(), None))
+    }
+
+    /// Record where each entry of `batch` came from, unless it already is.
+    fn record(&mut self, batch: &[&Entry]) -> Result<(), Error> {
+        for entry in batch {
+            let info = TrashInfo {
+                path: entry.name.clone(),
+                deleted: entry.timestamp,
+            };
+            if Self::read_info(&entry.root, &entry.alias).as_ref() != Some(&info) {
+                Self::write_info(&entry.root, &entry.alias, &info, false)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for XdgStorage {
+    fn lock(&mut self, cfg: &Config) -> Result<Option<Lock>, Error> {
+        Lock::acquire(cfg.lock()).map(Some)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        FsStorage.exists(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        FsStorage.is_dir(path)
+    }
+
+    fn create_dir_all(&mut self, dir: &Path) -> Result<(), Error> {
+        FsStorage.create_dir_all(dir)
+    }
+
+    fn discard(&mut self, path: &Path) -> Result<(), Error> {
+        FsStorage.discard(path)
+    }
+
+    /// The name of the file, followed by `.1`, `.2`, ... if it is taken.
+    fn alias_for(&self, root: &Root, file: &Path) -> Option<String> {
+        let base = file.file_name()?;
+        let mut id = 0;
+        loop {
+            let mut name = base.to_os_string();
+            if id > 0 {
+                name.push(format!(".{}", id));
+            }
+            let alias = encode(name.as_bytes());
+            if !self.contains(root, &alias) {
+                return Some(alias);
+            }
+            id += 1;
+        }
+    }
+
+    fn describe(&self, op: &Op, entry: &Entry) -> Vec<String> {
+        let file = Self::file(&entry.root, &entry.alias);
+        let info = Self::info(&entry.root, &entry.alias);
+        match op {
+            Op::Remove => vec![
+                format!("Register data as '{}'", info.display()),
+                format!("Move '{}' to '{}'", entry.name.display(), file.display()),
+            ],
+            Op::Restore(dest) => vec![
+                format!("Move '{}' to '{}'", file.display(), dest.display()),
+                format!("Delete '{}'", info.display()),
+            ],
+            Op::Purge => vec![
+                format!("Delete '{}'", file.display()),
+                format!("Delete '{}'", info.display()),
+            ],
+        }
+    }
+
+    fn store(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        file: &Path,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.import(root, alias, file, file, timestamp)
+    }
+
+    fn import(
+        &mut self,
+        root: &Root,
+        alias: &str,
+        file: &Path,
+        name: &Path,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let info = TrashInfo {
+            path: name.to_path_buf(),
+            deleted: timestamp,
+        };
+        Self::write_info(root, alias, &info, true)?;
+        let dest = Self::file(root, alias);
+        let files = root.root().join("files");
+        let moved =
+            std::fs::create_dir_all(&files).and_then(|_| crate::transfer::move_path(file, &dest));
+        match moved {
+            Ok(moved) => {
+                crate::storage::leftover(file, moved);
+                Ok(())
+            }
+            Err(_) => {
+                let _ = std::fs::remove_file(Self::info(root, alias));
+                Err(Error::CouldNotMove(
+                    file.to_string_lossy().to_string(),
+                    dest.to_string_lossy().to_string(),
+                ))
+            }
+        }
+    }
+
+    fn contains(&self, root: &Root, alias: &str) -> bool {
+        self.intact(root, alias) || self.exists(&Self::info(root, alias))
+    }
+
+    fn intact(&self, root: &Root, alias: &str) -> bool {
+        self.exists(&Self::file(root, alias))
+    }
+
+    fn aliases(&self, root: &Root) -> Vec<String> {
+        let names = |dir: &str| {
+            std::fs::read_dir(root.root().join(dir))
+                .map(|dir| {
+                    dir.filter_map(Result::ok)
+                        .map(|e| e.file_name())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        let mut aliases = names("files")
+            .into_iter()
+            .chain(names("info").into_iter().filter_map(|name| {
+                let name = name.as_bytes().strip_suffix(EXTENSION.as_bytes())?;
+                Some(OsStr::from_bytes(name).to_os_string())
+            }))
+            .map(|name| encode(name.as_bytes()))
+            .collect::<Vec<_>>();
+        aliases.sort();
+        aliases.dedup();
+        aliases
+    }
+
+    /// Collected from the file as it is now, which is what it was when
+    /// removed as far as anyone can tell.
+    fn meta(&self, root: &Root, alias: &str) -> Option<String> {
+        let info = Self::read_info(root, alias)?;
+        let mut meta = Meta::collect(&Self::file(root, alias), info.deleted).ok()?;
+        meta.name = info.path;
+        Some(meta.serialize())
+    }
+
+    fn size_of(&self, path: &Path) -> u64 {
+        FsStorage.size_of(path)
+    }
+
+    fn stored_size(&self, root: &Root, alias: &str) -> u64 {
+        crate::meta::total_size(&Self::file(root, alias))
+    }
+
+    fn stored_file(&self, root: &Root, alias: &str) -> PathBuf {
+        Self::file(root, alias)
+    }
+
+    fn retrieve(&mut self, root: &Root, alias: &str, dest: &Path) -> Result<(), Error> {
+        let file = Self::file(root, alias);
+        // Whatever is left in `files` goes along with the info
+        let moved = crate::transfer::move_path(&file, dest).is_ok() && self.exists(dest);
+        if !moved {
+            return Err(Error::CouldNotMove(
+                file.to_string_lossy().to_string(),
+                dest.to_string_lossy().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The file goes first, so that what is left of an interrupted removal
+    /// is still described.
+    fn remove(&mut self, root: &Root, alias: &str) -> Result<(), Error> {
+        let file = Self::file(root, alias);
+        if self.exists(&file) {
+            self.discard(&file)?;
+        }
+        let info = Self::info(root, alias);
+        match std::fs::remove_file(&info) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(Error::CouldNotRemove(info.to_string_lossy().to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rebuilt from the `.trashinfo` files, those that cannot be read are
+    /// left out.
+    fn read_history(&self, root: &Root) -> Result<Vec<u8>, Error> {
+        let mut batches = BTreeMap::<u64, Vec<Entry>>::new();
+        for alias in self.aliases(root) {
+            if let Some(info) = Self::read_info(root, &alias) {
+                batches.entry(info.deleted).or_default().push(Entry {
+                    name: info.path,
+                    alias,
+                    timestamp: info.deleted,
+                    root: root.clone(),
+                    batch: None,
+                });
+            }
+        }
+        let blocks = batches
+            .values()
+            .map(|batch| batch.iter().collect())
+            .collect::<Vec<_>>();
+        Ok(crate::history::format(&blocks).into_bytes())
+    }
+
+    /// Batches cannot be recorded, only where each entry came from and
+    /// when. Entries that are left out are not forgotten until removed.
+    fn write_history(&mut self, _root: &Root, blocks: &[Vec<&Entry>]) -> Result<(), Error> {
+        for batch in blocks {
+            self.record(batch)?;
+        }
+        Ok(())
+    }
+
+    fn append_batch(&mut self, _root: &Root, batch: &[&Entry]) -> Result<(), Error> {
+        self.record(batch)
+    }
+
+    fn append_log(&mut self, path: &Path, text: &[u8]) -> Result<(), Error> {
+        FsStorage.append_log(path, text)
+    }
+
+    fn read_log(&self, path: &Path) -> Vec<u8> {
+        FsStorage.read_log(path)
+    }
+
+    fn remove_log(&mut self, path: &Path) -> Result<(), Error> {
+        FsStorage.remove_log(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::select::Entries;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn trashinfo() {
+        let trash = Path::new("/home/user/.local/share/Trash");
+        for name in &["/plain", "/with space/and%", "/caf\u{e9}|\n"] {
+            let info = TrashInfo {
+                path: PathBuf::from(name),
+                deleted: 1_626_000_000,
+            };
+            assert_eq!(TrashInfo::parse(&info.serialize(), trash), Some(info));
+        }
+        let written = "[Trash Info]\nPath=foo/caf%C3%A9\nDeletionDate=2021-07-11T12:30:00\n";
+        let info = TrashInfo::parse(written, Path::new("/media/usb/.Trash-1000")).unwrap();
+        assert_eq!(info.path, Path::new("/media/usb/foo/café"));
+        assert_eq!(format_date(info.deleted), "2021-07-11T12:30:00");
+        assert_eq!(TrashInfo::parse("[Trash Info]\nPath=/a\n", trash), None);
+        assert_eq!(
+            TrashInfo::parse("Path=/a\nDeletionDate=2021-07-11T12:30:00", trash),
+            None
+        );
+        assert_eq!(decode("%zz"), None);
+    }
+
+    #[test]
+    fn shared_trash() {
+        let dir = TempDir::new("xdg");
+        let trash = dir.join("Trash");
+        let cfg = Config::new(dir.join("rem"))
+            .unwrap()
+            .with_xdg(Some(trash.clone()));
+        let root = cfg.roots().next().unwrap().clone();
+        let mut storage = XdgStorage;
+        // Trashed by another tool
+        std::fs::create_dir_all(trash.join("files/a b")).unwrap();
+        std::fs::create_dir_all(trash.join("info")).unwrap();
+        let info = format!(
+            "[Trash Info]\nPath={}\nDeletionDate=2021-07-11T12:30:00\n",
+            encode(dir.join("a b").as_os_str().as_bytes())
+        );
+        std::fs::write(trash.join("info/a b.trashinfo"), info).unwrap();
+        let name = dir.join("a b");
+        std::fs::write(&name, "contents").unwrap();
+        let alias = storage.alias_for(&root, &name).unwrap();
+        assert_eq!(alias, "a%20b.1");
+        storage.store(&root, &alias, &name, 1_700_000_000).unwrap();
+        let entry = Entry {
+            name: name.clone(),
+            alias: alias.clone(),
+            timestamp: 1_700_000_000,
+            root: root.clone(),
+            batch: None,
+        };
+        assert!(trash.join("files/a b.1").is_file());
+        assert_eq!(
+            TrashInfo::parse(
+                &std::fs::read_to_string(trash.join("info/a b.1.trashinfo")).unwrap(),
+                &trash
+            ),
+            Some(TrashInfo {
+                path: name.clone(),
+                deleted: 1_700_000_000
+            })
+        );
+        storage.append_batch(&root, &[&entry]).unwrap();
+        let entries = Entries::load(&storage, &cfg).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.get(1), Some(&entry));
+        assert_eq!(entries.get(2).unwrap().alias, "a%20b");
+        assert_eq!(entries.get(2).unwrap().name, name);
+        assert_eq!(
+            Meta::parse(&storage.meta(&root, "a%20b").unwrap())
+                .unwrap()
+                .name,
+            name
+        );
+        storage.retrieve(&root, &alias, &name).unwrap();
+        storage.remove(&root, &alias).unwrap();
+        assert_eq!(std::fs::read_to_string(&name).unwrap(), "contents");
+        assert!(!trash.join("info/a b.1.trashinfo").exists());
+        storage.remove(&root, "a%20b").unwrap();
+        assert_eq!(storage.aliases(&root), Vec::<String>::new());
+    }
+}